@@ -0,0 +1,82 @@
+//! Benchmarks for the primality path, run with `cargo bench`.
+//!
+//! Each benchmark is run for a fixed time and reports the mean time per
+//! iteration, giving a baseline to compare the sieve and cache against.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use num_bigint::{BigInt, Sign};
+use prime_time::{handle_request, is_number_prime, NumPrimeChecker, PrimalityChecker};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// How long each benchmark is measured for, after warming up for a tenth of it
+const MEASURE_FOR: Duration = Duration::from_secs(2);
+
+// Run `f` repeatedly and print the mean time it took
+fn bench<T>(name: &str, mut f: impl FnMut() -> T) {
+    let warm_up = Instant::now();
+    while warm_up.elapsed() < MEASURE_FOR / 10 {
+        black_box(f());
+    }
+
+    let mut iterations = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < MEASURE_FOR {
+        black_box(f());
+        iterations += 1;
+    }
+
+    let mean = start.elapsed() / iterations;
+    println!("{name:<30} {mean:>12.2?}/iter ({iterations} iterations)");
+}
+
+fn main() {
+    // 10^199 + 153 is the smallest prime with 200 digits
+    let prime_200_digits = BigInt::from(10).pow(199) + 153;
+    // the product of two ~100 digit primes, which no small factor gives away
+    let composite_199_digits =
+        (BigInt::from(10).pow(99) + 289) * (BigInt::from(2) * BigInt::from(10).pow(99) + 279);
+
+    let small_composite = BigInt::from(1_000_000);
+    let small_prime = BigInt::from(999_983);
+
+    bench("small composite", || {
+        is_number_prime(black_box(&small_composite))
+    });
+    bench("small prime", || is_number_prime(black_box(&small_prime)));
+    bench("200 digit prime", || {
+        is_number_prime(black_box(&prime_200_digits))
+    });
+    bench("199 digit composite", || {
+        is_number_prime(black_box(&composite_199_digits))
+    });
+
+    // random 256 bit odd composites, the common case trial division is for.
+    // A fixed seed keeps runs comparable.
+    let mut rng = StdRng::seed_from_u64(7);
+    let checker = NumPrimeChecker::default();
+    let composites: Vec<BigInt> = std::iter::repeat_with(|| {
+        let mut bytes: [u8; 32] = rng.gen();
+        bytes[31] |= 1;
+        BigInt::from_bytes_be(Sign::Plus, &bytes)
+    })
+    .filter(|n| !checker.is_prime(n))
+    .take(1000)
+    .collect();
+
+    for (name, checker) in [
+        ("random composites", checker),
+        ("  without trial division", checker.trial_division(false)),
+    ] {
+        let mut numbers = composites.iter().cycle();
+        bench(name, || {
+            checker.is_prime(black_box(numbers.next().unwrap()))
+        });
+    }
+
+    let request = format!(r#"{{"method":"isPrime","number":{prime_200_digits}}}"#);
+    bench("handle_request", || handle_request(black_box(&request)));
+}
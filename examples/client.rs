@@ -0,0 +1,34 @@
+//! Ask a running server whether numbers are prime.
+//!
+//! Start the server with `cargo run`, then run
+//! `cargo run --example client -- 7919 561 170141183460469231731687303715884105727`.
+//! Set `PRIME_TIME_ADDR` to talk to a server somewhere other than
+//! 127.0.0.1:8080.
+
+use num_bigint::BigInt;
+use prime_time::{PrimeClient, PrimeTimeError};
+
+#[tokio::main]
+async fn main() -> Result<(), PrimeTimeError> {
+    let addr = std::env::var("PRIME_TIME_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let addr = addr
+        .parse()
+        .map_err(|e| PrimeTimeError::InvalidConfig(format!("PRIME_TIME_ADDR: {e}")))?;
+
+    let mut client = PrimeClient::connect(addr).await?;
+
+    for arg in std::env::args().skip(1) {
+        let Ok(n) = arg.parse::<BigInt>() else {
+            eprintln!("{arg} is not an integer");
+            continue;
+        };
+
+        let verdict = match client.is_prime(&n).await? {
+            true => "prime",
+            false => "not prime",
+        };
+        println!("{n} is {verdict}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,21 @@
+//! Feeds arbitrary bytes to the request path. Any panic is a bug, every input
+//! has to come back as a response or an error. See fuzz/README.md for how to
+//! run it.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use num_bigint::BigInt;
+
+fuzz_target!(|data: &[u8]| {
+    // the server only hands lines that are valid UTF-8 to the handler
+    if let Ok(line) = std::str::from_utf8(data) {
+        let _ = prime_time::handle_request(line);
+    }
+
+    // numbers of any sign and size the JSON layer could produce, capped so
+    // a single input can't take seconds to check
+    if data.len() <= 64 {
+        let n = BigInt::from_signed_bytes_be(data);
+        let _ = prime_time::is_number_prime(&n);
+    }
+});
@@ -0,0 +1,436 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use num_bigint::BigInt;
+
+// Most servers have fewer cores than this, so connections rarely wait on
+// each other for a shard's lock
+const MAX_SHARDS: usize = 16;
+
+/// Somewhere to keep primality results for numbers the sieve can't answer,
+/// so the backend can be swapped out, for example for one shared between
+/// several servers. [`PrimeCache`] is the in-memory one used by default.
+pub trait ResultCache: Debug + Send + Sync {
+    /// The stored result for `n`, if there is one
+    fn get(&self, n: &BigInt) -> Option<bool>;
+
+    /// Store the result for `n`
+    fn put(&self, n: &BigInt, prime: bool);
+
+    /// Look up the result for `n`, calling `compute` and storing its result
+    /// on a miss. Backends that can tell when another caller is already
+    /// computing `n` may wait for that instead.
+    fn get_or_compute(&self, n: &BigInt, compute: &dyn Fn(&BigInt) -> bool) -> bool {
+        if let Some(prime) = self.get(n) {
+            return prime;
+        }
+
+        let prime = compute(n);
+        self.put(n, prime);
+        prime
+    }
+}
+
+/// A bounded cache of primality results, shared between connections.
+///
+/// Entries are spread over several independently locked shards so
+/// connections checking different numbers don't contend. Within a shard the
+/// least recently used entry is evicted once it is full, and entries can also
+/// be given a time to live after which they are checked again. A number that is
+/// already being checked isn't checked again, callers asking for it wait
+/// for the first check to finish.
+pub struct PrimeCache {
+    shards: Vec<Mutex<Shard>>,
+    ttl: Option<Duration>,
+}
+
+struct Shard {
+    // results and when they were stored
+    entries: LruCache<BigInt, (bool, Instant)>,
+    // numbers being computed right now
+    in_flight: HashMap<BigInt, Arc<InFlight>>,
+}
+
+// A computation other callers can wait on
+#[derive(Default)]
+struct InFlight {
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+#[derive(Default, Clone, Copy)]
+enum State {
+    #[default]
+    Computing,
+    Done(bool),
+    // the computation panicked, waiters have to compute it themselves
+    Abandoned,
+}
+
+impl PrimeCache {
+    /// Create a cache holding at most `capacity` results
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self::with_ttl(capacity, None)
+    }
+
+    /// Like [`PrimeCache::new`], but results older than `ttl` are treated as
+    /// missing and dropped
+    pub fn with_ttl(capacity: NonZeroUsize, ttl: Option<Duration>) -> Self {
+        let capacity = capacity.get();
+        let shards = capacity.min(MAX_SHARDS);
+
+        // split the capacity as evenly as possible, every shard gets at
+        // least one entry since there are no more shards than entries
+        let shards = (0..shards)
+            .map(|i| {
+                let size = capacity / shards + usize::from(i < capacity % shards);
+                Mutex::new(Shard {
+                    entries: LruCache::new(
+                        NonZeroUsize::new(size).expect("shard size is at least 1"),
+                    ),
+                    in_flight: HashMap::new(),
+                })
+            })
+            .collect();
+
+        Self { shards, ttl }
+    }
+
+    /// Look up the result for `n`, calling `compute` and storing its result on
+    /// a miss. If another caller is already computing `n` this blocks until
+    /// it is done and returns its result.
+    pub fn get_or_compute(&self, n: &BigInt, compute: impl FnOnce(&BigInt) -> bool) -> bool {
+        let shard = self.shard(n);
+
+        let in_flight = {
+            let mut shard = lock(shard);
+
+            match shard.entries.get(n) {
+                Some(&(prime, stored)) if !self.expired(stored) => return prime,
+                Some(_) => {
+                    shard.entries.pop(n);
+                }
+                None => (),
+            }
+
+            match shard.in_flight.get(n) {
+                Some(in_flight) => Some(in_flight.clone()),
+                None => {
+                    shard
+                        .in_flight
+                        .insert(n.clone(), Arc::new(InFlight::default()));
+                    None
+                }
+            }
+        };
+
+        if let Some(in_flight) = in_flight {
+            return match in_flight.wait() {
+                Some(prime) => prime,
+                None => self.get_or_compute(n, compute),
+            };
+        }
+
+        // don't hold the lock while computing, other connections may need it.
+        // The guard wakes any waiters even if computing panics.
+        let mut guard = Computing {
+            cache: self,
+            shard,
+            n,
+            result: None,
+        };
+        let prime = compute(n);
+        guard.result = Some(prime);
+
+        prime
+    }
+
+    /// Number of results currently cached
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| lock(shard).entries.len())
+            .sum()
+    }
+
+    /// Whether the cache holds no results
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn expired(&self, stored: Instant) -> bool {
+        self.ttl.is_some_and(|ttl| stored.elapsed() >= ttl)
+    }
+
+    // Drop expired entries from the least recently used end of a shard. Ones
+    // that were used more recently are dropped when they are next looked up.
+    fn remove_expired(&self, shard: &mut Shard) {
+        while let Some((_, &(_, stored))) = shard.entries.peek_lru() {
+            if !self.expired(stored) {
+                break;
+            }
+            shard.entries.pop_lru();
+        }
+    }
+
+    fn shard(&self, n: &BigInt) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        n.hash(&mut hasher);
+
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+impl ResultCache for PrimeCache {
+    fn get(&self, n: &BigInt) -> Option<bool> {
+        let mut shard = lock(self.shard(n));
+
+        match shard.entries.get(n) {
+            Some(&(prime, stored)) if !self.expired(stored) => Some(prime),
+            Some(_) => {
+                shard.entries.pop(n);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, n: &BigInt, prime: bool) {
+        let mut shard = lock(self.shard(n));
+
+        self.remove_expired(&mut shard);
+        shard.entries.put(n.clone(), (prime, Instant::now()));
+    }
+
+    // waits for callers already computing `n`
+    fn get_or_compute(&self, n: &BigInt, compute: &dyn Fn(&BigInt) -> bool) -> bool {
+        PrimeCache::get_or_compute(self, n, compute)
+    }
+}
+
+impl Debug for PrimeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrimeCache")
+            .field("len", &self.len())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // a panic while holding the lock can't leave the cache inconsistent
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl InFlight {
+    // Block until the computation finishes, None if it was abandoned
+    fn wait(&self) -> Option<bool> {
+        let mut state = lock(&self.state);
+
+        loop {
+            match *state {
+                State::Computing => {
+                    state = self.finished.wait(state).unwrap_or_else(|e| e.into_inner())
+                }
+                State::Done(prime) => return Some(prime),
+                State::Abandoned => return None,
+            }
+        }
+    }
+}
+
+// Publishes the result of a computation when dropped, storing it in the
+// cache and waking everyone waiting on it
+struct Computing<'a> {
+    cache: &'a PrimeCache,
+    shard: &'a Mutex<Shard>,
+    n: &'a BigInt,
+    result: Option<bool>,
+}
+
+impl Drop for Computing<'_> {
+    fn drop(&mut self) {
+        let in_flight = {
+            let mut shard = lock(self.shard);
+
+            if let Some(prime) = self.result {
+                self.cache.remove_expired(&mut shard);
+                shard.entries.put(self.n.clone(), (prime, Instant::now()));
+            }
+            shard.in_flight.remove(self.n)
+        };
+
+        if let Some(in_flight) = in_flight {
+            *lock(&in_flight.state) = match self.result {
+                Some(prime) => State::Done(prime),
+                None => State::Abandoned,
+            };
+            in_flight.finished.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_number_hits_cache() {
+        let cache = PrimeCache::new(NonZeroUsize::new(8).unwrap());
+        let mut computed = 0;
+
+        for _ in 0..3 {
+            let prime = cache.get_or_compute(&BigInt::from(7), |_| {
+                computed += 1;
+                true
+            });
+            assert!(prime);
+        }
+
+        assert_eq!(computed, 1);
+    }
+
+    #[test]
+    fn test_get_and_put() {
+        let cache = PrimeCache::new(NonZeroUsize::new(8).unwrap());
+        let n = BigInt::from(7);
+
+        assert_eq!(cache.get(&n), None);
+        cache.put(&n, true);
+        assert_eq!(cache.get(&n), Some(true));
+
+        // put results are used by get_or_compute too
+        assert!(cache.get_or_compute(&n, |_| unreachable!()));
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        // a single shard, so eviction order is exact
+        let cache = PrimeCache::new(NonZeroUsize::new(1).unwrap());
+
+        cache.get_or_compute(&BigInt::from(2), |_| true);
+        cache.get_or_compute(&BigInt::from(3), |_| true);
+
+        assert_eq!(cache.len(), 1);
+
+        // 2 was evicted, so it has to be computed again
+        let mut computed = false;
+        cache.get_or_compute(&BigInt::from(2), |_| {
+            computed = true;
+            true
+        });
+        assert!(computed);
+    }
+
+    #[test]
+    fn test_expired_entry_is_computed_again() {
+        let ttl = Duration::from_millis(50);
+        let cache = PrimeCache::with_ttl(NonZeroUsize::new(8).unwrap(), Some(ttl));
+        let mut computed = 0;
+        let mut check = || {
+            cache.get_or_compute(&BigInt::from(7), |_| {
+                computed += 1;
+                true
+            })
+        };
+
+        check();
+        check();
+        std::thread::sleep(ttl * 2);
+        check();
+
+        assert_eq!(computed, 2);
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped_on_insert() {
+        // a single shard, so every insert sees the others
+        let ttl = Duration::from_millis(50);
+        let cache = PrimeCache {
+            shards: vec![Mutex::new(Shard {
+                entries: LruCache::new(NonZeroUsize::new(8).unwrap()),
+                in_flight: HashMap::new(),
+            })],
+            ttl: Some(ttl),
+        };
+
+        for n in 0..4 {
+            cache.get_or_compute(&BigInt::from(n), |_| false);
+        }
+        std::thread::sleep(ttl * 2);
+        cache.get_or_compute(&BigInt::from(4), |_| false);
+
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_capacity_is_bounded_across_shards() {
+        let cache = PrimeCache::new(NonZeroUsize::new(100).unwrap());
+
+        for n in 0..1000 {
+            cache.get_or_compute(&BigInt::from(n), |_| false);
+        }
+
+        assert!(cache.len() <= 100);
+    }
+
+    #[test]
+    fn test_concurrent_callers_compute_once() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Barrier,
+            },
+            time::Duration,
+        };
+
+        let cache = PrimeCache::new(NonZeroUsize::new(8).unwrap());
+        let computed = AtomicUsize::new(0);
+        let barrier = Barrier::new(8);
+        let n = (BigInt::from(1) << 127) - 1;
+
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        cache.get_or_compute(&n, |_| {
+                            computed.fetch_add(1, Ordering::SeqCst);
+                            // long enough for every thread to ask
+                            std::thread::sleep(Duration::from_millis(100));
+                            true
+                        })
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                assert!(handle.join().unwrap());
+            }
+        });
+
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_panicking_computation_lets_others_retry() {
+        let cache = PrimeCache::new(NonZeroUsize::new(8).unwrap());
+        let n = BigInt::from(7);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cache.get_or_compute(&n, |_| panic!("checker failed"))
+        }));
+        assert!(result.is_err());
+
+        // nothing was cached and nothing is left waiting
+        assert!(cache.is_empty());
+        assert!(cache.get_or_compute(&n, |_| true));
+    }
+}
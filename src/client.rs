@@ -0,0 +1,129 @@
+use std::net::SocketAddr;
+
+use num_bigint::BigInt;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{PrimeTimeError, Request, RequestNumber, Response};
+
+/// A client for the line protocol, one JSON request per line answered by one
+/// JSON response per line.
+///
+/// ```no_run
+/// use num_bigint::BigInt;
+/// use prime_time::PrimeClient;
+///
+/// # async fn example() -> Result<(), prime_time::PrimeTimeError> {
+/// let mut client = PrimeClient::connect("127.0.0.1:8080".parse().unwrap()).await?;
+///
+/// // sends {"method":"isPrime","number":7919}
+/// assert!(client.is_prime(&BigInt::from(7919)).await?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PrimeClient<S = TcpStream> {
+    stream: BufReader<S>,
+    line: String,
+}
+
+impl PrimeClient {
+    /// Connect to a server over TCP
+    pub async fn connect(addr: SocketAddr) -> Result<Self, PrimeTimeError> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PrimeClient<S> {
+    /// Talk to a server over an already connected stream, like a Unix socket
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            line: String::new(),
+        }
+    }
+
+    /// Ask the server whether `n` is prime.
+    ///
+    /// The server closes the connection after a malformed response, so once
+    /// this returns [`PrimeTimeError::UnexpectedResponse`] the client can't
+    /// be used again.
+    pub async fn is_prime(&mut self, n: &BigInt) -> Result<bool, PrimeTimeError> {
+        let request = Request::new("isPrime", RequestNumber::BigInt(n.clone()));
+
+        self.stream
+            .write_all(request.to_json_line().as_bytes())
+            .await?;
+        self.stream.flush().await?;
+
+        let response: Response = self.response().await?;
+
+        Ok(response.prime)
+    }
+
+    // Read the next response line and parse it as a `T`
+    async fn response<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, PrimeTimeError> {
+        self.line.clear();
+        if self.stream.read_line(&mut self.line).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let line = self.line.trim_end();
+        serde_json::from_str(line).map_err(|_| PrimeTimeError::UnexpectedResponse(line.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::TestServer, ServerConfig};
+
+    #[tokio::test]
+    async fn test_is_prime() {
+        let server = TestServer::spawn(ServerConfig::default()).await;
+        let mut client = PrimeClient::connect(server.addr).await.unwrap();
+
+        // either side of the test server's sieve limit
+        for (n, prime) in [(7, true), (91, false), (7919, true), (7917, false)] {
+            assert_eq!(
+                client.is_prime(&BigInt::from(n)).await.unwrap(),
+                prime,
+                "{n}"
+            );
+        }
+
+        let mersenne = (BigInt::from(1) << 127) - 1;
+        assert!(client.is_prime(&mersenne).await.unwrap());
+        assert!(!client.is_prime(&-mersenne).await.unwrap());
+
+        drop(client);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_response() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = PrimeClient::new(client);
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let _ = tokio::io::AsyncReadExt::read(&mut server, &mut buf).await;
+            server.write_all(b"Invalid JSON\n").await.unwrap();
+        });
+
+        let result = client.is_prime(&BigInt::from(7)).await;
+        assert!(
+            matches!(&result, Err(PrimeTimeError::UnexpectedResponse(line)) if line == "Invalid JSON"),
+            "{result:?}"
+        );
+
+        // the server has hung up
+        let result = client.is_prime(&BigInt::from(7)).await;
+        assert!(
+            matches!(result, Err(PrimeTimeError::IOError(_))),
+            "{result:?}"
+        );
+    }
+}
@@ -0,0 +1,939 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    NumPrimeChecker, PrimalityAlgorithm, PrimalityChecker, PrimeTimeError, RateLimit, ResultCache,
+};
+
+/// Settings that control how the server behaves.
+///
+/// Build one with [`ServerConfig::builder`], any setting that isn't given
+/// keeps its default.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use prime_time::ServerConfig;
+///
+/// # async fn example() -> Result<(), prime_time::PrimeTimeError> {
+/// let config = ServerConfig::builder()
+///     .addr("0.0.0.0:9000".parse().unwrap())
+///     .max_connections(64)
+///     .idle_timeout(Duration::from_secs(10))
+///     .build()?;
+///
+/// prime_time::run_with_config(config).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// It can also be loaded from a TOML file with [`ServerConfig::from_toml`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on
+    pub addr: SocketAddr,
+    /// When listening on the IPv6 wildcard address `::`, also accept IPv4
+    /// connections. Some platforms only do this when asked to.
+    pub dual_stack: bool,
+    /// Disable Nagle's algorithm on accepted connections. Responses are
+    /// small lines that would otherwise be held back waiting to be combined
+    /// with more data, adding latency to every request.
+    pub tcp_nodelay: bool,
+    /// Send TCP keepalive probes on connections that have been quiet this
+    /// long, so peers that vanished behind a NAT or load balancer are noticed
+    /// and their connections closed. Off unless given, and given in seconds
+    /// in config files.
+    ///
+    /// Quiet connections are already closed after
+    /// [`idle_timeout`](Self::idle_timeout), so this only catches dead peers
+    /// sooner when it is shorter than that. The peer is given up on after the
+    /// operating system's number of unanswered probes.
+    #[serde(deserialize_with = "deserialize_opt_secs")]
+    pub tcp_keepalive: Option<Duration>,
+    /// Time between keepalive probes once they start, the operating system's
+    /// default unless given. Only used with
+    /// [`tcp_keepalive`](Self::tcp_keepalive), and ignored on platforms that
+    /// can't set it.
+    #[serde(deserialize_with = "deserialize_opt_secs")]
+    pub tcp_keepalive_interval: Option<Duration>,
+    /// When serving on a Unix socket, remove a socket file left at the path
+    /// by a server that didn't shut down cleanly
+    pub unlink_stale_socket: bool,
+    /// Maximum number of connections handled at once. Further connections
+    /// wait in the listen backlog until a slot frees up.
+    pub max_connections: usize,
+    /// Connections the operating system queues for the server to accept.
+    /// A bigger backlog helps when many clients connect at once. The kernel
+    /// silently caps it, on Linux at `net.core.somaxconn` and on macOS at
+    /// `kern.ipc.somaxconn`.
+    pub listen_backlog: u32,
+    /// Listeners opened on the same address with `SO_REUSEPORT`, each with
+    /// its own accept loop, so the kernel spreads new connections across
+    /// them. Worth raising on machines with many cores where a single accept
+    /// loop can't keep up. Only supported on Linux, elsewhere one listener
+    /// is opened whatever this is. Must be at least 1.
+    pub accept_shards: usize,
+    /// How long a connection may go without sending a request before it is
+    /// closed. The timer restarts after every request. Given in seconds in
+    /// config files.
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub idle_timeout: Duration,
+    /// How long a new connection gets to send its first complete request
+    /// before it is closed, so clients can't hold connections open by
+    /// dribbling a line a byte at a time. Off unless given, only worth
+    /// setting shorter than [`idle_timeout`](Self::idle_timeout). Given in
+    /// seconds in config files.
+    #[serde(deserialize_with = "deserialize_opt_secs")]
+    pub first_request_timeout: Option<Duration>,
+    /// How long a single request may take to answer. Requests that take
+    /// longer get a malformed response, though the check itself carries on
+    /// in the background. Checking a huge number can take minutes. Given in
+    /// seconds in config files.
+    #[serde(deserialize_with = "deserialize_opt_secs")]
+    pub request_timeout: Option<Duration>,
+    /// How long writing responses to a client may block before the
+    /// connection is closed, so a client that stops reading can't hold on
+    /// to it. Off unless given. Given in seconds in config files.
+    #[serde(deserialize_with = "deserialize_opt_secs")]
+    pub write_timeout: Option<Duration>,
+    /// Threads answering requests, shared by every connection. Requests
+    /// beyond these wait their turn, so a burst of huge numbers can't
+    /// oversubscribe the CPUs. Defaults to the number of CPU cores.
+    pub compute_workers: usize,
+    /// Close the connection when a request times out, instead of carrying on
+    /// with the next request
+    pub close_on_request_timeout: bool,
+    /// Stop waiting on a request when the client closes the connection
+    /// before it is answered, releasing the connection straight away.
+    /// Factoring and `nextPrime` searches stop early, a single primality
+    /// check runs to completion in the background and its result still ends
+    /// up in the cache.
+    ///
+    /// Off by default: a client that shuts down its sending side right after
+    /// writing its requests looks the same as one that went away, and would
+    /// get none of its answers.
+    pub cancel_on_disconnect: bool,
+    /// Requests answered on one connection before it is closed, so a single
+    /// client can't hold a connection slot forever. Unlimited unless given.
+    pub max_requests_per_connection: Option<usize>,
+    /// How long connections get to finish when the server shuts down, after
+    /// which they are aborted. Given in seconds in config files.
+    #[serde(deserialize_with = "deserialize_secs")]
+    pub shutdown_grace: Duration,
+    /// Longest request line accepted, including the delimiter. Longer lines are
+    /// treated as malformed. With [`FramingMode::LengthPrefixed`] this is the
+    /// largest frame accepted, not counting its length prefix.
+    pub max_line_bytes: usize,
+    /// How requests and responses are delimited on connections
+    pub framing: FramingMode,
+    /// The byte ending each request and response with
+    /// [`FramingMode::NewlineDelimited`], `\n` unless a client needs
+    /// something else like NUL. It must be an ASCII control character, since
+    /// any other byte can appear inside a JSON request. Given as a number in
+    /// config files, like `0` for NUL.
+    pub delimiter: u8,
+    /// Silently drop a last line that the client closed the connection
+    /// without finishing, instead of answering it as malformed. Either way
+    /// an unfinished line is never parsed.
+    pub drop_unterminated_line: bool,
+    /// Expect every connection to start with a PROXY protocol v1 header, as
+    /// sent by HAProxy or an AWS load balancer, and log the client address
+    /// it gives instead of the balancer's. Rate limits apply to that address
+    /// too. Connections without a valid header are closed. Only turn this
+    /// on behind a balancer that sends the header, anyone else could claim
+    /// any address.
+    pub proxy_protocol: bool,
+    /// Log the request and response at INFO for one in this many requests
+    /// on a connection, starting with the first. Malformed requests and
+    /// their responses are always logged. 1 logs every request.
+    pub log_sample_rate: u64,
+    /// Largest number accepted, in decimal digits. Parsing and testing a
+    /// number with millions of digits would tie the server up, so longer
+    /// numbers are treated as malformed. Numbers over a million digits are
+    /// refused whatever this is set to.
+    pub max_number_digits: usize,
+    /// Number of primality results kept in the cache shared by all
+    /// connections. Zero disables the cache.
+    pub cache_size: usize,
+    /// How long a cached result is kept, forever unless given. Expired
+    /// results are dropped when they are next looked up or when new results
+    /// are stored, the cache never holds more than
+    /// [`cache_size`](Self::cache_size) either way.
+    #[serde(deserialize_with = "deserialize_opt_secs")]
+    pub cache_ttl: Option<Duration>,
+    /// Numbers below this are answered from a sieve built at startup instead
+    /// of the probabilistic test. `countPrimesBelow` answers numbers up to
+    /// this and no further.
+    pub sieve_limit: usize,
+    /// Before accepting connections, store every prime from
+    /// [`sieve_limit`](Self::sieve_limit) up to this bound in the cache, so
+    /// the first requests for them don't wait on the primality test. Off
+    /// when zero or not above the sieve limit. Only as many primes as the
+    /// cache holds are kept.
+    pub warm_cache_below: usize,
+    /// Treat floats with no fractional part, like `7.0`, as integers. Off by
+    /// default since the spec says only integers can be prime. Floats above
+    /// 2^53 are never treated as integers because they can't be represented
+    /// exactly.
+    pub treat_integral_floats_as_int: bool,
+    /// Accept several requests on one line with nothing between them, like
+    /// `{...}{...}`, answering each on its own line. Off by default, the spec
+    /// has one request per line.
+    pub concatenated_requests: bool,
+    /// Answer requests that can't be answered with a JSON object like
+    /// `{"error":"invalid_request","detail":"..."}` instead of the bare line
+    /// `Invalid JSON`. Off by default, the spec only asks for a malformed
+    /// response.
+    pub structured_errors: bool,
+    /// Indent JSON responses over several lines with
+    /// `serde_json::to_string_pretty`, for reading them by hand in `curl`.
+    /// Only the HTTP server supports it, since the Content-Length frames its
+    /// responses. The line protocol servers refuse to start with it, as
+    /// clients reading one response per line couldn't read it. Off by
+    /// default.
+    pub pretty_responses: bool,
+    /// Answer `{"method":"stats"}` with the server-wide request counters and
+    /// uptime. Off by default since any client could read them.
+    pub stats_method: bool,
+    /// Answer `{"method":"factorize","number":n}` with the prime factors of
+    /// `n`. Off by default since factoring can take far longer than a
+    /// primality test. Numbers with more than
+    /// [`max_factorize_digits`](Self::max_factorize_digits) digits, and
+    /// numbers with several factors too large to find quickly, are answered
+    /// as malformed.
+    pub factorize_method: bool,
+    /// Largest number `factorize` accepts, in decimal digits
+    pub max_factorize_digits: usize,
+    /// Largest number `nextPrime` accepts, in decimal digits. The search
+    /// checks a few hundred candidates for a 100 digit number, and gets
+    /// slower much faster than the numbers get longer.
+    pub max_next_prime_digits: usize,
+    /// Add the smallest prime factor of composite numbers to `isPrime`
+    /// responses as `"factor"`. Off by default to keep to the spec's response
+    /// shape. Numbers with several factors too large to find quickly get
+    /// no factor.
+    pub composite_factor: bool,
+    /// Add the requested number to `isPrime` responses as `"number"`, so a
+    /// client pipelining requests can tell the responses apart. Integers
+    /// are sent back exactly, however large. Off by default to keep to the
+    /// spec's response shape.
+    pub echo_number: bool,
+    /// Add the bit length of the number to `isPrime` and `factorize`
+    /// responses as `"bits"`, for clients profiling the sizes they send.
+    /// Floats get none. Off by default to keep to the spec's response
+    /// shape.
+    pub number_bits: bool,
+    /// Add the count of decimal digits in the number, leaving out its
+    /// sign, to `isPrime` and `factorize` responses as `"digits"`. Floats
+    /// get none. Off by default to keep to the spec's response shape.
+    pub number_digits: bool,
+    /// Treat requests with fields other than `method` and `number` as
+    /// malformed. Off by default since the spec says extra fields are
+    /// ignored. `ping` and `stats` requests are still answered whatever
+    /// else they hold.
+    pub strict_fields: bool,
+    /// Rounds of Miller-Rabin run on numbers of 2^64 and above, which are
+    /// too large for the deterministic test. Each round lets a composite
+    /// through with probability at most 1/4, so `n` rounds make a wrong
+    /// answer at most 4^-n likely, at the cost of one modular
+    /// exponentiation per round, around 20ms for a 1000 digit prime. A prime
+    /// is only answered once every round has run, so the cost grows with
+    /// both the rounds and [`max_number_digits`](Self::max_number_digits).
+    /// Must be at least 1. When not given the rounds grow with the number up
+    /// to [`NumPrimeChecker::MAX_ROUNDS`], see [`NumPrimeChecker`].
+    ///
+    /// This configures the default [`NumPrimeChecker`]. Setting it with
+    /// [`ServerConfigBuilder::miller_rabin_rounds`] or in a config file
+    /// replaces [`ServerConfig::primality`] with one using that many rounds
+    /// on every number.
+    pub miller_rabin_rounds: Option<usize>,
+    /// Which primality test to run, see [`PrimalityAlgorithm`] for how they
+    /// compare. Setting it with [`ServerConfigBuilder::primality_algorithm`]
+    /// or in a config file replaces [`ServerConfig::primality`].
+    pub primality_algorithm: PrimalityAlgorithm,
+    /// The primality test used for numbers the sieve can't answer. It can't
+    /// be set from a config file.
+    #[serde(skip)]
+    pub primality: Arc<dyn PrimalityChecker>,
+    /// Where primality results are cached instead of the in-memory
+    /// [`PrimeCache`](crate::PrimeCache), for example a cache shared between
+    /// servers. [`cache_size`](Self::cache_size) and
+    /// [`cache_ttl`](Self::cache_ttl) don't apply to it. It can't be set
+    /// from a config file.
+    #[serde(skip)]
+    pub result_cache: Option<Arc<dyn ResultCache>>,
+    /// Limit on how fast each client IP address may send requests. Requests
+    /// over the limit are delayed until the client is back under it.
+    pub rate_limit: Option<RateLimit>,
+    /// Address to serve Prometheus metrics on over HTTP, if any
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl ServerConfig {
+    /// Start building a config from the defaults
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    /// Parse a config from TOML. Settings that aren't given keep their
+    /// defaults, unknown settings are an error.
+    ///
+    /// ```
+    /// let config = prime_time::ServerConfig::from_toml(
+    ///     r#"
+    ///     addr = "0.0.0.0:9000"
+    ///     idle_timeout = 10
+    ///
+    ///     [rate_limit]
+    ///     requests_per_second = 50.0
+    ///     burst = 100
+    ///     "#,
+    /// )
+    /// .unwrap();
+    ///
+    /// assert_eq!(config.addr.port(), 9000);
+    /// ```
+    pub fn from_toml(toml: &str) -> Result<Self, PrimeTimeError> {
+        let value = crate::toml::parse(toml).map_err(PrimeTimeError::InvalidConfig)?;
+        let mut config: Self = serde_json::from_value(value)
+            .map_err(|e| PrimeTimeError::InvalidConfig(e.to_string()))?;
+
+        config.validate()?;
+
+        // the checker can't be given in the file, so it's set up from the
+        // chosen algorithm and rounds
+        config.primality = config
+            .primality_algorithm
+            .checker(config.miller_rabin_rounds);
+
+        Ok(config)
+    }
+
+    // Reject settings the server can't run with
+    pub(crate) fn validate(&self) -> Result<(), PrimeTimeError> {
+        let invalid = |msg: &str| Err(PrimeTimeError::InvalidConfig(msg.to_string()));
+
+        if self.max_connections == 0 {
+            return invalid("max_connections must be at least 1");
+        }
+        if self.max_line_bytes == 0 {
+            return invalid("max_line_bytes must be at least 1");
+        }
+        if !self.delimiter.is_ascii_control() || self.delimiter == 0x7f {
+            return invalid("delimiter must be an ASCII control character");
+        }
+        if self.accept_shards == 0 {
+            return invalid("accept_shards must be at least 1");
+        }
+        if self.compute_workers == 0 {
+            return invalid("compute_workers must be at least 1");
+        }
+        if self.log_sample_rate == 0 {
+            return invalid("log_sample_rate must be at least 1");
+        }
+        if self.miller_rabin_rounds == Some(0) {
+            return invalid("miller_rabin_rounds must be at least 1");
+        }
+        if self.cache_ttl == Some(Duration::ZERO) {
+            return invalid("cache_ttl must be greater than zero");
+        }
+        if self.max_requests_per_connection == Some(0) {
+            return invalid("max_requests_per_connection must be at least 1");
+        }
+        if let Some(limit) = self.rate_limit {
+            let rate = limit.requests_per_second;
+            if rate.is_nan() || rate <= 0.0 || limit.burst == 0 {
+                return invalid("rate_limit must allow at least one request");
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like validate, for serving the line protocol, where responses are
+    // framed by the delimiter
+    pub(crate) fn validate_lines(&self) -> Result<(), PrimeTimeError> {
+        self.validate()?;
+
+        if self.pretty_responses {
+            return Err(PrimeTimeError::InvalidConfig(
+                "pretty_responses is only supported by the HTTP server".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// How messages are delimited on a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FramingMode {
+    /// One request per line, as the protocol specifies. Lines end with
+    /// [`ServerConfig::delimiter`].
+    #[default]
+    NewlineDelimited,
+    /// Each message is a 4-byte big-endian length followed by that many
+    /// bytes holding one JSON request. Responses are framed the same way,
+    /// without the trailing newline.
+    LengthPrefixed,
+}
+
+// Read a duration given as a number of seconds
+fn deserialize_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
+
+// Read a duration given as a number of seconds, for settings that are off
+// unless given
+fn deserialize_opt_secs<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_secs(deserializer).map(Some)
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)),
+            dual_stack: true,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            tcp_keepalive_interval: None,
+            unlink_stale_socket: true,
+            max_connections: 1024,
+            listen_backlog: 1024,
+            accept_shards: 1,
+            idle_timeout: Duration::from_secs(30),
+            first_request_timeout: None,
+            request_timeout: None,
+            write_timeout: None,
+            compute_workers: std::thread::available_parallelism()
+                .map_or(1, std::num::NonZeroUsize::get),
+            close_on_request_timeout: false,
+            cancel_on_disconnect: false,
+            max_requests_per_connection: None,
+            shutdown_grace: Duration::from_secs(10),
+            max_line_bytes: 1024 * 1024,
+            framing: FramingMode::NewlineDelimited,
+            delimiter: b'\n',
+            drop_unterminated_line: false,
+            proxy_protocol: false,
+            log_sample_rate: 1,
+            max_number_digits: 1000,
+            cache_size: 10_000,
+            cache_ttl: None,
+            sieve_limit: 1_000_000,
+            warm_cache_below: 0,
+            treat_integral_floats_as_int: false,
+            concatenated_requests: false,
+            structured_errors: false,
+            pretty_responses: false,
+            stats_method: false,
+            factorize_method: false,
+            max_factorize_digits: 40,
+            max_next_prime_digits: 100,
+            composite_factor: false,
+            echo_number: false,
+            number_bits: false,
+            number_digits: false,
+            strict_fields: false,
+            miller_rabin_rounds: None,
+            primality_algorithm: PrimalityAlgorithm::default(),
+            primality: Arc::new(NumPrimeChecker::default()),
+            result_cache: None,
+            rate_limit: None,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+        }
+    }
+}
+
+/// Builder for [`ServerConfig`]
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+/// Start building from an existing config, such as one loaded from a file
+impl From<ServerConfig> for ServerConfigBuilder {
+    fn from(config: ServerConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl ServerConfigBuilder {
+    /// See [`ServerConfig::addr`]
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.config.addr = addr;
+        self
+    }
+
+    /// See [`ServerConfig::dual_stack`]
+    pub fn dual_stack(mut self, enabled: bool) -> Self {
+        self.config.dual_stack = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::tcp_nodelay`]
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.config.tcp_nodelay = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::tcp_keepalive`]
+    pub fn tcp_keepalive(mut self, time: Duration) -> Self {
+        self.config.tcp_keepalive = Some(time);
+        self
+    }
+
+    /// See [`ServerConfig::tcp_keepalive_interval`]
+    pub fn tcp_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.config.tcp_keepalive_interval = Some(interval);
+        self
+    }
+
+    /// See [`ServerConfig::unlink_stale_socket`]
+    pub fn unlink_stale_socket(mut self, enabled: bool) -> Self {
+        self.config.unlink_stale_socket = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::max_connections`]
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    /// See [`ServerConfig::listen_backlog`]
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.config.listen_backlog = backlog;
+        self
+    }
+
+    /// See [`ServerConfig::accept_shards`]
+    pub fn accept_shards(mut self, shards: usize) -> Self {
+        self.config.accept_shards = shards;
+        self
+    }
+
+    /// See [`ServerConfig::idle_timeout`]
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// See [`ServerConfig::first_request_timeout`]
+    pub fn first_request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.first_request_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`ServerConfig::request_timeout`]
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.config.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// See [`ServerConfig::write_timeout`]
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`ServerConfig::compute_workers`]
+    pub fn compute_workers(mut self, workers: usize) -> Self {
+        self.config.compute_workers = workers;
+        self
+    }
+
+    /// See [`ServerConfig::close_on_request_timeout`]
+    pub fn close_on_request_timeout(mut self, enabled: bool) -> Self {
+        self.config.close_on_request_timeout = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::cancel_on_disconnect`]
+    pub fn cancel_on_disconnect(mut self, enabled: bool) -> Self {
+        self.config.cancel_on_disconnect = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::max_requests_per_connection`]
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        self.config.max_requests_per_connection = Some(max_requests);
+        self
+    }
+
+    /// See [`ServerConfig::shutdown_grace`]
+    pub fn shutdown_grace(mut self, shutdown_grace: Duration) -> Self {
+        self.config.shutdown_grace = shutdown_grace;
+        self
+    }
+
+    /// See [`ServerConfig::max_line_bytes`]
+    pub fn max_line_bytes(mut self, max_line_bytes: usize) -> Self {
+        self.config.max_line_bytes = max_line_bytes;
+        self
+    }
+
+    /// See [`ServerConfig::framing`]
+    pub fn framing(mut self, framing: FramingMode) -> Self {
+        self.config.framing = framing;
+        self
+    }
+
+    /// See [`ServerConfig::delimiter`]
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.config.delimiter = delimiter;
+        self
+    }
+
+    /// See [`ServerConfig::drop_unterminated_line`]
+    pub fn drop_unterminated_line(mut self, enabled: bool) -> Self {
+        self.config.drop_unterminated_line = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::proxy_protocol`]
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.config.proxy_protocol = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::log_sample_rate`]
+    pub fn log_sample_rate(mut self, rate: u64) -> Self {
+        self.config.log_sample_rate = rate;
+        self
+    }
+
+    /// See [`ServerConfig::max_number_digits`]
+    pub fn max_number_digits(mut self, max_number_digits: usize) -> Self {
+        self.config.max_number_digits = max_number_digits;
+        self
+    }
+
+    /// See [`ServerConfig::cache_size`]
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.config.cache_size = cache_size;
+        self
+    }
+
+    /// See [`ServerConfig::cache_ttl`]
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.config.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// See [`ServerConfig::sieve_limit`]
+    pub fn sieve_limit(mut self, sieve_limit: usize) -> Self {
+        self.config.sieve_limit = sieve_limit;
+        self
+    }
+
+    /// See [`ServerConfig::warm_cache_below`]
+    pub fn warm_cache_below(mut self, bound: usize) -> Self {
+        self.config.warm_cache_below = bound;
+        self
+    }
+
+    /// See [`ServerConfig::treat_integral_floats_as_int`]
+    pub fn treat_integral_floats_as_int(mut self, enabled: bool) -> Self {
+        self.config.treat_integral_floats_as_int = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::concatenated_requests`]
+    pub fn concatenated_requests(mut self, enabled: bool) -> Self {
+        self.config.concatenated_requests = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::structured_errors`]
+    pub fn structured_errors(mut self, enabled: bool) -> Self {
+        self.config.structured_errors = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::pretty_responses`]
+    pub fn pretty_responses(mut self, enabled: bool) -> Self {
+        self.config.pretty_responses = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::stats_method`]
+    pub fn stats_method(mut self, enabled: bool) -> Self {
+        self.config.stats_method = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::factorize_method`]
+    pub fn factorize_method(mut self, enabled: bool) -> Self {
+        self.config.factorize_method = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::max_factorize_digits`]
+    pub fn max_factorize_digits(mut self, max_digits: usize) -> Self {
+        self.config.max_factorize_digits = max_digits;
+        self
+    }
+
+    /// See [`ServerConfig::max_next_prime_digits`]
+    pub fn max_next_prime_digits(mut self, max_digits: usize) -> Self {
+        self.config.max_next_prime_digits = max_digits;
+        self
+    }
+
+    /// See [`ServerConfig::composite_factor`]
+    pub fn composite_factor(mut self, enabled: bool) -> Self {
+        self.config.composite_factor = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::echo_number`]
+    pub fn echo_number(mut self, enabled: bool) -> Self {
+        self.config.echo_number = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::number_bits`]
+    pub fn number_bits(mut self, enabled: bool) -> Self {
+        self.config.number_bits = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::number_digits`]
+    pub fn number_digits(mut self, enabled: bool) -> Self {
+        self.config.number_digits = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::strict_fields`]
+    pub fn strict_fields(mut self, enabled: bool) -> Self {
+        self.config.strict_fields = enabled;
+        self
+    }
+
+    /// See [`ServerConfig::miller_rabin_rounds`]
+    pub fn miller_rabin_rounds(mut self, rounds: usize) -> Self {
+        self.config.miller_rabin_rounds = Some(rounds);
+        self.config.primality = self.config.primality_algorithm.checker(Some(rounds));
+        self
+    }
+
+    /// See [`ServerConfig::primality_algorithm`]
+    pub fn primality_algorithm(mut self, algorithm: PrimalityAlgorithm) -> Self {
+        self.config.primality_algorithm = algorithm;
+        self.config.primality = algorithm.checker(self.config.miller_rabin_rounds);
+        self
+    }
+
+    /// See [`ServerConfig::primality`]
+    pub fn primality(mut self, checker: impl PrimalityChecker + 'static) -> Self {
+        self.config.primality = Arc::new(checker);
+        self
+    }
+
+    /// See [`ServerConfig::result_cache`]
+    pub fn result_cache(mut self, cache: impl ResultCache + 'static) -> Self {
+        self.config.result_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// See [`ServerConfig::rate_limit`]
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.config.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// See [`ServerConfig::metrics_addr`]
+    #[cfg(feature = "metrics")]
+    pub fn metrics_addr(mut self, metrics_addr: SocketAddr) -> Self {
+        self.config.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    /// Finish building the config, failing if a setting is one the server
+    /// can't run with
+    pub fn build(self) -> Result<ServerConfig, PrimeTimeError> {
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_overrides_defaults() {
+        let addr: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+
+        let config = ServerConfig::builder()
+            .addr(addr)
+            .max_connections(8)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.addr, addr);
+        assert_eq!(config.max_connections, 8);
+        assert_eq!(config.cache_size, ServerConfig::default().cache_size);
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        let cases = [
+            ServerConfig::builder().max_connections(0),
+            ServerConfig::builder().max_line_bytes(0),
+            ServerConfig::builder().delimiter(b' '),
+            ServerConfig::builder().accept_shards(0),
+            ServerConfig::builder().compute_workers(0),
+            ServerConfig::builder().log_sample_rate(0),
+            ServerConfig::builder().miller_rabin_rounds(0),
+            ServerConfig::builder().cache_ttl(Duration::ZERO),
+            ServerConfig::builder().max_requests_per_connection(0),
+            ServerConfig::builder().rate_limit(RateLimit {
+                requests_per_second: 0.0,
+                burst: 5,
+            }),
+        ];
+
+        for builder in cases {
+            let debug = format!("{builder:?}");
+            assert!(
+                matches!(builder.build(), Err(PrimeTimeError::InvalidConfig(_))),
+                "{debug}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let config = ServerConfig::from_toml(
+            r#"
+            addr = "[::]:9000"
+            idle_timeout = 2.5
+            request_timeout = 1
+            treat_integral_floats_as_int = true
+
+            [rate_limit]
+            requests_per_second = 10
+            burst = 5
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.addr, "[::]:9000".parse().unwrap());
+        assert_eq!(config.idle_timeout, Duration::from_millis(2500));
+        assert_eq!(config.request_timeout, Some(Duration::from_secs(1)));
+        assert!(config.treat_integral_floats_as_int);
+        assert_eq!(
+            config.rate_limit,
+            Some(RateLimit {
+                requests_per_second: 10.0,
+                burst: 5
+            })
+        );
+        assert_eq!(config.cache_size, ServerConfig::default().cache_size);
+    }
+
+    #[test]
+    fn test_miller_rabin_rounds_reach_the_checker() {
+        let config = ServerConfig::from_toml("miller_rabin_rounds = 12").unwrap();
+        assert_eq!(
+            format!("{:?}", config.primality),
+            format!("{:?}", NumPrimeChecker::with_rounds(12))
+        );
+
+        let config = ServerConfig::builder()
+            .miller_rabin_rounds(3)
+            .build()
+            .unwrap();
+        assert_eq!(config.miller_rabin_rounds, Some(3));
+        assert_eq!(
+            format!("{:?}", config.primality),
+            format!("{:?}", NumPrimeChecker::with_rounds(3))
+        );
+    }
+
+    #[test]
+    fn test_primality_algorithm_reaches_the_checker() {
+        let config = ServerConfig::from_toml("primality_algorithm = \"bpsw\"").unwrap();
+        assert_eq!(config.primality_algorithm, PrimalityAlgorithm::Bpsw);
+        assert_eq!(
+            format!("{:?}", config.primality),
+            format!("{:?}", crate::BpswChecker)
+        );
+
+        // the rounds are kept for when Miller-Rabin is chosen again
+        let config = ServerConfig::builder()
+            .miller_rabin_rounds(7)
+            .primality_algorithm(PrimalityAlgorithm::Trial)
+            .build()
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", config.primality),
+            format!("{:?}", crate::TrialDivisionChecker)
+        );
+
+        let config = ServerConfigBuilder::from(config)
+            .primality_algorithm(PrimalityAlgorithm::MillerRabin)
+            .build()
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", config.primality),
+            format!("{:?}", NumPrimeChecker::with_rounds(7))
+        );
+    }
+
+    #[test]
+    fn test_from_toml_example_file() {
+        ServerConfig::from_toml(include_str!("../config.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn test_from_toml_invalid() {
+        let cases = [
+            "addr = \"not an address\"",
+            "max_conections = 10",
+            "max_connections = -1",
+            "max_connections = 0",
+            "idle_timeout = \"30s\"",
+            "[rate_limit]\nburst = 5",
+            "cache_size = ",
+            "miller_rabin_rounds = 0",
+            "max_requests_per_connection = 0",
+            "log_sample_rate = 0",
+            "compute_workers = 0",
+            "accept_shards = 0",
+            "primality_algorithm = \"fermat\"",
+            "delimiter = 32",
+            "delimiter = 256",
+            "primality_algorithm = \"miller_rabin\"",
+        ];
+
+        for toml in cases {
+            assert!(
+                matches!(
+                    ServerConfig::from_toml(toml),
+                    Err(PrimeTimeError::InvalidConfig(_))
+                ),
+                "{toml}"
+            );
+        }
+    }
+}
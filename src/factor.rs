@@ -0,0 +1,221 @@
+use num_bigint::{BigInt, BigUint};
+use num_integer::Integer;
+use num_traits::{One, Zero};
+
+use crate::{PrimalityChecker, Sieve};
+
+// Iterations of Pollard's rho spent on one number before giving up. Rho
+// takes about the square root of a factor's size to find it, so this is
+// enough for factors of up to 8 digits.
+const RHO_ITERATIONS: u64 = 1 << 16;
+
+/// The prime factors of `n` in ascending order, repeated by multiplicity.
+/// 1 has no prime factors. `n` must not be zero.
+///
+/// Small factors are found by trial division with the primes in `sieve`,
+/// whatever is left over is split with Pollard's rho until `checker` calls
+/// each piece prime. `None` if rho runs out of iterations first, which
+/// happens when `n` has two or more factors too large to find quickly.
+pub fn factorize(
+    n: &BigUint,
+    sieve: &Sieve,
+    checker: &dyn PrimalityChecker,
+) -> Option<Vec<BigUint>> {
+    assert!(!n.is_zero(), "0 has no factorization");
+
+    let mut factors = Vec::new();
+    let mut n = n.clone();
+
+    // 2 is handled on its own since rho struggles with powers of two
+    while n.is_even() {
+        factors.push(BigUint::from(2u32));
+        n >>= 1;
+    }
+
+    for p in sieve.primes().skip(1) {
+        let p = BigUint::from(p);
+
+        // once p^2 > n, what is left of n is 1 or a prime
+        if &p * &p > n {
+            break;
+        }
+
+        while (&n % &p).is_zero() {
+            n /= &p;
+            factors.push(p.clone());
+        }
+    }
+
+    let mut budget = RHO_ITERATIONS;
+    split(n, checker, &mut factors, &mut budget)?;
+    factors.sort();
+
+    Some(factors)
+}
+
+/// The smallest prime factor of `n`, or `None` for 0 and 1.
+///
+/// Trial division with the primes in `sieve` finds small factors without
+/// factoring the rest of `n`. If there are none, `n` is fully factored with
+/// Pollard's rho, which gives up with `None` like [`factorize`] when every
+/// factor is large.
+pub fn smallest_factor(
+    n: &BigUint,
+    sieve: &Sieve,
+    checker: &dyn PrimalityChecker,
+) -> Option<BigUint> {
+    if n.is_zero() || n.is_one() {
+        return None;
+    }
+
+    if n.is_even() {
+        return Some(BigUint::from(2u32));
+    }
+
+    for p in sieve.primes().skip(1) {
+        let p = BigUint::from(p);
+
+        if &p * &p > *n {
+            // n is prime
+            return Some(n.clone());
+        }
+        if (n % &p).is_zero() {
+            return Some(p);
+        }
+    }
+
+    let mut factors = Vec::new();
+    let mut budget = RHO_ITERATIONS;
+    split(n.clone(), checker, &mut factors, &mut budget)?;
+    factors.into_iter().min()
+}
+
+// Recursively split `n` into the factors `checker` calls prime, spending rho
+// iterations from `budget`. None once the budget runs out.
+fn split(
+    n: BigUint,
+    checker: &dyn PrimalityChecker,
+    factors: &mut Vec<BigUint>,
+    budget: &mut u64,
+) -> Option<()> {
+    if n.is_one() {
+        return Some(());
+    }
+
+    if checker.is_prime(&BigInt::from(n.clone())) {
+        factors.push(n);
+        return Some(());
+    }
+
+    let divisor = pollard_rho(&n, budget)?;
+    let cofactor = &n / &divisor;
+
+    split(divisor, checker, factors, budget)?;
+    split(cofactor, checker, factors, budget)
+}
+
+// Find a nontrivial divisor of the odd composite `n`, trying x^2 + c for
+// increasing c until one works. None if the budget runs out or the request
+// is cancelled first.
+fn pollard_rho(n: &BigUint, budget: &mut u64) -> Option<BigUint> {
+    let mut c = BigUint::one();
+
+    loop {
+        let f = |x: &BigUint| (x * x + &c) % n;
+
+        let mut x = BigUint::from(2u32);
+        let mut y = x.clone();
+        let mut d = BigUint::one();
+
+        while d.is_one() {
+            *budget = budget.checked_sub(1)?;
+            if crate::pool::cancelled() {
+                return None;
+            }
+
+            x = f(&x);
+            y = f(&f(&y));
+
+            let diff = if x > y { &x - &y } else { &y - &x };
+            d = diff.gcd(n);
+        }
+
+        if &d != n {
+            return Some(d);
+        }
+
+        c += 1u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NumPrimeChecker;
+
+    fn factors(n: u64, sieve: &Sieve) -> Vec<u64> {
+        factorize(&BigUint::from(n), sieve, &NumPrimeChecker::default())
+            .unwrap()
+            .into_iter()
+            .map(|f| f.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_factorize_small() {
+        let sieve = Sieve::new(100);
+
+        assert_eq!(factors(360, &sieve), [2, 2, 2, 3, 3, 5]);
+        assert_eq!(factors(97, &sieve), [97]);
+        assert_eq!(factors(1, &sieve), [] as [u64; 0]);
+    }
+
+    #[test]
+    fn test_factorize_perfect_power() {
+        let sieve = Sieve::new(100);
+
+        // 3^10
+        assert_eq!(factors(59049, &sieve), [3; 10]);
+    }
+
+    #[test]
+    fn test_smallest_factor() {
+        let smallest = |n: u64, sieve: &Sieve| {
+            smallest_factor(&BigUint::from(n), sieve, &NumPrimeChecker::default())
+                .map(|f| u64::try_from(f).unwrap())
+        };
+        let sieve = Sieve::new(100);
+
+        assert_eq!(smallest(15, &sieve), Some(3));
+        assert_eq!(smallest(1024, &sieve), Some(2));
+        assert_eq!(smallest(97, &sieve), Some(97));
+        assert_eq!(smallest(1, &sieve), None);
+
+        // beyond the sieve, rho may find the larger factor first
+        assert_eq!(smallest(1_000_003 * 999_983, &sieve), Some(999_983));
+        assert_eq!(smallest(1_000_003 * 999_983, &Sieve::new(0)), Some(999_983));
+    }
+
+    #[test]
+    fn test_factorize_without_sieve() {
+        // every factor has to come from rho
+        let sieve = Sieve::new(0);
+
+        assert_eq!(factors(1_000_003 * 999_983, &sieve), [999_983, 1_000_003]);
+        assert_eq!(
+            factors(1024 * 49, &sieve),
+            [2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 7, 7]
+        );
+    }
+
+    #[test]
+    fn test_rho_gives_up_on_large_factors() {
+        // the product of two 20 digit primes
+        let n: BigUint = "100000000000000001380000000000000004437".parse().unwrap();
+        let sieve = Sieve::new(100);
+        let checker = NumPrimeChecker::default();
+
+        assert_eq!(factorize(&n, &sieve, &checker), None);
+        assert_eq!(smallest_factor(&n, &sieve, &checker), None);
+    }
+}
@@ -0,0 +1,1698 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, OnceLock},
+};
+
+use num_bigint::{BigInt, BigUint, Sign};
+use num_integer::Integer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    factor::{factorize, smallest_factor},
+    pool::ComputePool,
+    protocol::{integer_digits, StrictRequest},
+    CountPrimesBelowResponse, ErrorResponse, FactorizeResponse, Metrics, NextPrimeResponse,
+    PingResponse, PrimeCache, PrimeTimeError, RateLimiter, Request, RequestNumber, Response,
+    ResultCache, ServerConfig, Sieve, StatsResponse,
+};
+
+/// Handle a single JSON request line and produce the JSON response.
+///
+/// The returned string has no line ending, the server terminates it with
+/// [`ServerConfig::delimiter`] when writing it to the connection.
+///
+/// A line holding a JSON array is a batch: each element is answered in turn
+/// and the responses are returned as an array. If any element is malformed
+/// the whole batch is.
+///
+/// ```
+/// let response = prime_time::handle_request(r#"{"method":"isPrime","number":7}"#).unwrap();
+/// assert_eq!(response, "{\"method\":\"isPrime\",\"prime\":true}");
+/// ```
+pub fn handle_request(json: &str) -> Result<String, PrimeTimeError> {
+    // a one-off request isn't worth building a sieve or cache for
+    let config = ServerConfig {
+        sieve_limit: 0,
+        cache_size: 0,
+        ..Default::default()
+    };
+
+    RequestHandler::new(&config).handle(json)
+}
+
+/// Answers requests using a sieve and cache that are shared by all connections
+pub struct RequestHandler {
+    config: ServerConfig,
+    sieve: Sieve,
+    cache: Option<Arc<dyn ResultCache>>,
+    metrics: Arc<Metrics>,
+    rate_limiter: Option<RateLimiter>,
+    // started on first use, one-off requests don't need it
+    pool: OnceLock<ComputePool>,
+}
+
+impl RequestHandler {
+    /// Build the sieve and cache described by `config`
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            config: config.clone(),
+            sieve: Sieve::new(config.sieve_limit),
+            cache: config.result_cache.clone().or_else(|| {
+                let capacity = NonZeroUsize::new(config.cache_size)?;
+                Some(Arc::new(PrimeCache::with_ttl(capacity, config.cache_ttl)))
+            }),
+            metrics: Arc::default(),
+            rate_limiter: config.rate_limit.map(RateLimiter::new),
+            pool: OnceLock::new(),
+        }
+    }
+
+    /// Fill the cache with the primes between the sieve limit and
+    /// [`ServerConfig::warm_cache_below`], returning how many were stored.
+    /// Does nothing without a cache.
+    pub fn warm_up(&self) -> usize {
+        let Some(cache) = &self.cache else {
+            return 0;
+        };
+
+        let sieve = Sieve::new(self.config.warm_cache_below);
+        let mut warmed = 0;
+
+        for prime in sieve.primes().skip_while(|&p| p < self.sieve.limit()) {
+            cache.put(&BigInt::from(prime), true);
+            warmed += 1;
+        }
+
+        warmed
+    }
+
+    // The threads requests are answered on
+    pub(crate) fn pool(&self) -> &ComputePool {
+        self.pool
+            .get_or_init(|| ComputePool::new(self.config.compute_workers))
+    }
+
+    /// Counters for the requests and connections this handler has seen
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// The per-address rate limiter, if rate limiting is enabled
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Like [`handle_request`], but primality is answered by [`Self::is_prime`]
+    ///
+    /// With [`ServerConfig::concatenated_requests`] set, a line can hold
+    /// several requests one after another, each getting its own response
+    /// line. The lines are separated by [`ServerConfig::delimiter`], the
+    /// last one is left for the server to terminate.
+    pub fn handle(&self, json: &str) -> Result<String, PrimeTimeError> {
+        let replies = self.process(json)?;
+        let mut lines = String::new();
+
+        for reply in &replies {
+            for answer in reply.answers() {
+                if let Answer::IsPrime(response) = answer {
+                    self.metrics.record_request(response.prime);
+                }
+            }
+
+            if !lines.is_empty() {
+                lines.push(char::from(self.config.delimiter));
+            }
+            lines.push_str(&self.to_json(reply)?);
+        }
+
+        Ok(lines)
+    }
+
+    // Serialize a response, indented if ServerConfig::pretty_responses is set
+    fn to_json(&self, response: &impl Serialize) -> serde_json::Result<String> {
+        if self.config.pretty_responses {
+            serde_json::to_string_pretty(response)
+        } else {
+            serde_json::to_string(response)
+        }
+    }
+
+    /// The line to send back for a request that failed with `error`. This is
+    /// `Invalid JSON` unless structured errors are enabled.
+    pub fn error_line(&self, error: &PrimeTimeError) -> String {
+        if !self.config.structured_errors {
+            return "Invalid JSON".to_string();
+        }
+
+        let kind = match error {
+            PrimeTimeError::Timeout(_) => "timeout",
+            PrimeTimeError::DeserializeError(_) => "invalid_json",
+            PrimeTimeError::IOError(_)
+            | PrimeTimeError::JoinError(_)
+            | PrimeTimeError::WorkerFailed => "internal",
+            _ => "invalid_request",
+        };
+
+        let response = ErrorResponse {
+            error: kind.to_string(),
+            detail: error.to_string(),
+        };
+
+        // serializing two strings can't fail
+        self.to_json(&response).expect("error response serializes")
+    }
+
+    /// Check whether a number is prime, consulting the sieve first and then the
+    /// cache before falling back to [`ServerConfig::primality`]
+    pub fn is_prime(&self, n: &BigInt) -> bool {
+        if let Some(prime) = self.sieve.lookup(n) {
+            return prime;
+        }
+
+        match &self.cache {
+            Some(cache) => cache.get_or_compute(n, &|n| self.check(n)),
+            None => self.check(n),
+        }
+    }
+
+    // Run the configured primality test in a span recording how big the
+    // number was and how long it took, to find the expensive requests. The
+    // clock is only read when the span is enabled.
+    fn check(&self, n: &BigInt) -> bool {
+        let span = tracing::debug_span!(
+            "primality",
+            bits = n.bits(),
+            elapsed = tracing::field::Empty
+        );
+        let _entered = span.enter();
+
+        if span.is_disabled() {
+            return self.config.primality.is_prime(n);
+        }
+
+        let start = std::time::Instant::now();
+        let prime = self.config.primality.is_prime(n);
+
+        let elapsed = start.elapsed();
+        span.record("elapsed", tracing::field::debug(elapsed));
+        tracing::debug!(prime, ?elapsed, "Checked primality");
+
+        prime
+    }
+
+    fn process(&self, json: &str) -> Result<Vec<Reply>, PrimeTimeError> {
+        // drop the line ending, including the \r some clients send before
+        // the \n
+        let json = json.trim_end_matches(['\r', '\n', char::from(self.config.delimiter)]);
+
+        if !self.config.concatenated_requests {
+            let mut deserializer = serde_json::Deserializer::from_str(json);
+            let request = Value::deserialize(&mut deserializer)?;
+
+            // only whitespace may follow the request
+            deserializer.end().map_err(|e| {
+                PrimeTimeError::MalformedRequest(format!(
+                    "unexpected data after the request at column {}",
+                    e.column()
+                ))
+            })?;
+
+            return Ok(vec![self.reply(request)?]);
+        }
+
+        let replies = serde_json::Deserializer::from_str(json)
+            .into_iter()
+            .map(|request| self.reply(request?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // a blank line is still malformed
+        if replies.is_empty() {
+            return Err(PrimeTimeError::MalformedRequest("empty line".to_string()));
+        }
+
+        Ok(replies)
+    }
+
+    // Answer one top level JSON value from a line
+    fn reply(&self, request: Value) -> Result<Reply, PrimeTimeError> {
+        match request {
+            // a top level array is a batch of requests
+            Value::Array(requests) => {
+                let answers = requests
+                    .into_iter()
+                    .map(|request| self.answer(request))
+                    .collect::<Result<_, _>>()?;
+
+                Ok(Reply::Batch(answers))
+            }
+            request => Ok(Reply::Single(self.answer(request)?)),
+        }
+    }
+
+    // Dispatch a request on its method. The method has to be looked at first
+    // since not every method needs a number.
+    fn answer(&self, request: Value) -> Result<Answer, PrimeTimeError> {
+        // the derived Deserialize would also take a sequence like
+        // ["isPrime",7], which would get past the digit check below
+        if !request.is_object() {
+            return Err(PrimeTimeError::MalformedRequest(
+                "request is not a JSON object".to_string(),
+            ));
+        }
+
+        if request.get("method").and_then(Value::as_str) == Some("ping") {
+            return Ok(Answer::Ping(PingResponse {
+                method: "ping".to_string(),
+                ok: true,
+            }));
+        }
+
+        if request.get("method").and_then(Value::as_str) == Some("stats") {
+            return self.answer_stats().map(Answer::Stats);
+        }
+
+        // refuse huge numbers before spending time parsing them
+        if let Some(Value::Number(number)) = request.get("number") {
+            let max = self.config.max_number_digits;
+
+            if integer_digits(&number.to_string()) > max as u64 {
+                return Err(PrimeTimeError::MalformedRequest(format!(
+                    "number has more than {max} digits"
+                )));
+            }
+        }
+
+        // the line is valid JSON, so anything wrong from here on is a protocol
+        // violation rather than a syntax error
+        let request = if self.config.strict_fields {
+            StrictRequest::deserialize(request).map(Request::from)
+        } else {
+            Request::deserialize(request)
+        }
+        .map_err(|e| PrimeTimeError::MalformedRequest(e.to_string()))?;
+
+        match request.method.as_str() {
+            "factorize" => Ok(Answer::Factorize(self.answer_factorize(request)?)),
+            "nextPrime" => Ok(Answer::NextPrime(self.answer_next_prime(request)?)),
+            "countPrimesBelow" => Ok(Answer::CountPrimesBelow(
+                self.answer_count_primes_below(request)?,
+            )),
+            _ => Ok(Answer::IsPrime(self.answer_is_prime(request)?)),
+        }
+    }
+
+    fn answer_stats(&self) -> Result<StatsResponse, PrimeTimeError> {
+        if !self.config.stats_method {
+            return Err(PrimeTimeError::MalformedRequest(
+                "stats are disabled".to_string(),
+            ));
+        }
+
+        Ok(StatsResponse {
+            method: "stats".to_string(),
+            requests: self.metrics.requests(),
+            primes: self.metrics.primes(),
+            composites: self.metrics.composites(),
+            malformed: self.metrics.malformed(),
+            malformed_reasons: self
+                .metrics
+                .malformed_by_reason()
+                .map(|(reason, count)| (reason.to_string(), count))
+                .collect(),
+            uptime_seconds: self.metrics.uptime().as_secs(),
+            started_at: self
+                .metrics
+                .started_at()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        })
+    }
+
+    fn answer_is_prime(&self, request: Request) -> Result<Response, PrimeTimeError> {
+        // only "isPrime" is a conforming method
+        if request.method != "isPrime" {
+            return Err(PrimeTimeError::MalformedRequest(format!(
+                "unknown method {:?}",
+                request.method
+            )));
+        }
+
+        let number = self.config.echo_number.then(|| request.number.clone());
+
+        let n = match request.number {
+            RequestNumber::Float(f) => match integral_float(f) {
+                Some(n) if self.config.treat_integral_floats_as_int => Some(n),
+                _ => None,
+            },
+            RequestNumber::BigInt(n) => Some(n),
+        };
+
+        // check if number is prime, floats never are
+        let prime = n.as_ref().is_some_and(|n| self.is_prime(n));
+        let (bits, digits) = self.size_of(n.as_ref());
+
+        // only numbers above 1 are composite
+        let factor = match n {
+            Some(n) if self.config.composite_factor && !prime && n.sign() == Sign::Plus => {
+                smallest_factor(n.magnitude(), &self.sieve, self.config.primality.as_ref())
+                    .map(BigInt::from)
+            }
+            _ => None,
+        };
+
+        Ok(Response {
+            method: request.method,
+            prime,
+            factor,
+            number,
+            bits,
+            digits,
+        })
+    }
+
+    // The bits and digits of `n` for a response, each only if asked for
+    fn size_of(&self, n: Option<&BigInt>) -> (Option<u64>, Option<u64>) {
+        let bits = n.filter(|_| self.config.number_bits).map(BigInt::bits);
+        let digits = n
+            .filter(|_| self.config.number_digits)
+            .map(|n| decimal_digits(n.magnitude()));
+
+        (bits, digits)
+    }
+
+    fn answer_factorize(&self, request: Request) -> Result<FactorizeResponse, PrimeTimeError> {
+        if !self.config.factorize_method {
+            return Err(PrimeTimeError::MalformedRequest(
+                "factorize is disabled".to_string(),
+            ));
+        }
+
+        // only positive integers can be factorized
+        let n = match request.number {
+            RequestNumber::BigInt(n) if n.sign() == Sign::Plus => n,
+            _ => {
+                return Err(PrimeTimeError::MalformedRequest(
+                    "factorize needs a positive integer".to_string(),
+                ))
+            }
+        };
+
+        let max = self.config.max_factorize_digits;
+        if decimal_digits(n.magnitude()) > max as u64 {
+            return Err(PrimeTimeError::MalformedRequest(format!(
+                "factorize is limited to numbers of up to {max} digits"
+            )));
+        }
+
+        let factors = factorize(n.magnitude(), &self.sieve, self.config.primality.as_ref())
+            .ok_or_else(|| {
+                PrimeTimeError::MalformedRequest("number has factors too large to find".to_string())
+            })?
+            .into_iter()
+            .map(BigInt::from)
+            .collect();
+        let (bits, digits) = self.size_of(Some(&n));
+
+        Ok(FactorizeResponse {
+            method: request.method,
+            factors,
+            bits,
+            digits,
+        })
+    }
+
+    fn answer_next_prime(&self, request: Request) -> Result<NextPrimeResponse, PrimeTimeError> {
+        let n = match request.number {
+            RequestNumber::BigInt(n) => n,
+            RequestNumber::Float(_) => {
+                return Err(PrimeTimeError::MalformedRequest(
+                    "nextPrime needs an integer".to_string(),
+                ))
+            }
+        };
+
+        let max = self.config.max_next_prime_digits;
+        if decimal_digits(n.magnitude()) > max as u64 {
+            return Err(PrimeTimeError::MalformedRequest(format!(
+                "nextPrime is limited to numbers of up to {max} digits"
+            )));
+        }
+
+        // nobody is waiting for the answer to a cancelled request
+        let next = self
+            .next_prime_unless_cancelled(&n)
+            .ok_or(PrimeTimeError::WorkerFailed)?;
+
+        Ok(NextPrimeResponse {
+            method: request.method,
+            next,
+        })
+    }
+
+    fn answer_count_primes_below(
+        &self,
+        request: Request,
+    ) -> Result<CountPrimesBelowResponse, PrimeTimeError> {
+        let n = match request.number {
+            RequestNumber::BigInt(n) if n.sign() != Sign::Minus => n,
+            _ => {
+                return Err(PrimeTimeError::MalformedRequest(
+                    "countPrimesBelow needs a non-negative integer".to_string(),
+                ))
+            }
+        };
+
+        // only the shared sieve is counted, sieving further for a request
+        // would cost a full sieve each time
+        let count = usize::try_from(&n)
+            .ok()
+            .and_then(|n| self.sieve.count_below(n))
+            .ok_or_else(|| {
+                PrimeTimeError::MalformedRequest(format!(
+                    "countPrimesBelow is limited to numbers up to {}",
+                    self.sieve.limit()
+                ))
+            })?;
+
+        Ok(CountPrimesBelowResponse {
+            method: request.method,
+            count: count as u64,
+        })
+    }
+
+    /// The smallest prime strictly greater than `n`. Every number below 2,
+    /// including negative ones, gives 2.
+    ///
+    /// Candidates are checked with the sieve while they are below its limit,
+    /// then with [`ServerConfig::primality`]. They skip the cache since each is only
+    /// asked about once.
+    pub fn next_prime(&self, n: &BigInt) -> BigInt {
+        self.next_prime_unless_cancelled(n)
+            .expect("only requests on the compute pool are cancelled")
+    }
+
+    // Like next_prime, but None if the request is cancelled before a prime
+    // is found
+    fn next_prime_unless_cancelled(&self, n: &BigInt) -> Option<BigInt> {
+        let two = BigInt::from(2);
+        if n < &two {
+            return Some(two);
+        }
+
+        // 2 is the only even prime, so start at the next odd number
+        let mut candidate: BigInt = n + 1;
+        if candidate.is_even() {
+            candidate += 1;
+        }
+
+        loop {
+            if crate::pool::cancelled() {
+                return None;
+            }
+
+            let prime = self
+                .sieve
+                .lookup(&candidate)
+                .unwrap_or_else(|| self.check(&candidate));
+
+            if prime {
+                return Some(candidate);
+            }
+
+            candidate += 2;
+        }
+    }
+}
+
+// The answer to one request, each method has its own response shape
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Answer {
+    IsPrime(Response),
+    Ping(PingResponse),
+    Stats(StatsResponse),
+    Factorize(FactorizeResponse),
+    NextPrime(NextPrimeResponse),
+    CountPrimesBelow(CountPrimesBelowResponse),
+}
+
+// The answer to a request line, a single answer or a batch of them
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Reply {
+    Single(Answer),
+    Batch(Vec<Answer>),
+}
+
+impl Reply {
+    fn answers(&self) -> &[Answer] {
+        match self {
+            Reply::Single(answer) => std::slice::from_ref(answer),
+            Reply::Batch(answers) => answers,
+        }
+    }
+}
+
+// Convert a float to an integer if it has no fractional part and is small
+// enough to be exact
+fn integral_float(f: f64) -> Option<BigInt> {
+    // above 2^53 neighbouring floats are more than 1 apart
+    const MAX_EXACT: f64 = (1u64 << 53) as f64;
+
+    if f.fract() != 0.0 || f.abs() > MAX_EXACT {
+        return None;
+    }
+
+    Some(BigInt::from(f as i64))
+}
+
+// The number of decimal digits in `n`. The bit length pins it down to one
+// of two values, so only one power of ten has to be built to choose.
+fn decimal_digits(n: &BigUint) -> u64 {
+    let bits = n.bits();
+    if bits == 0 {
+        return 1;
+    }
+
+    // 2^(bits - 1) <= n < 2^bits
+    let digits = ((bits - 1) as f64 * std::f64::consts::LOG10_2) as u64 + 1;
+    match u32::try_from(digits) {
+        Ok(exponent) if *n >= BigUint::from(10u32).pow(exponent) => digits + 1,
+        _ => digits,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // a handler with a small sieve so tests start quickly
+    fn test_handler() -> Arc<RequestHandler> {
+        let config = ServerConfig {
+            sieve_limit: 1000,
+            ..Default::default()
+        };
+        Arc::new(RequestHandler::new(&config))
+    }
+
+    #[test]
+    fn test_handle_request_composite() {
+        let input = r#"{ "method": "isPrime", "number": 18 }"#;
+        let output = r#"{"method":"isPrime","prime":false}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_prime() {
+        let input = r#"{ "method": "isPrime", "number": 178417 }"#;
+        let output = r#"{"method":"isPrime","prime":true}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_crlf() {
+        for input in [
+            "{\"method\":\"isPrime\",\"number\":7}\r\n",
+            "{\"method\":\"isPrime\",\"number\":7}\n",
+            "{\"method\":\"isPrime\",\"number\":7}\r",
+        ] {
+            assert_eq!(
+                handle_request(input).unwrap(),
+                "{\"method\":\"isPrime\",\"prime\":true}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_handle_request_extra_fields() {
+        let input = r#"{ "method": "isPrime", "number": 30, "yolo": "swag" }"#;
+        let output = r#"{"method":"isPrime","prime":false}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_strict_fields_rejects_extra_fields() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            strict_fields: true,
+            ..Default::default()
+        });
+
+        let input = r#"{ "method": "isPrime", "number": 7, "yolo": "swag" }"#;
+        let result = handler.handle(input);
+        assert!(
+            matches!(&result, Err(PrimeTimeError::MalformedRequest(e)) if e.contains("yolo")),
+            "{result:?}"
+        );
+
+        let input = r#"{ "method": "isPrime", "number": 7 }"#;
+        let output = "{\"method\":\"isPrime\",\"prime\":true}";
+        assert_eq!(handler.handle(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_bigint() {
+        let input = r#"{ "method": "isPrime", "number": 529830422160613455916930483453466154480529308265681626708 }"#;
+        let output = r#"{"method":"isPrime","prime":false}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_float() {
+        let input = r#"{ "method": "isPrime", "number": 1.234 }"#;
+        let output = r#"{"method":"isPrime","prime":false}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_scientific_notation() {
+        let input = r#"{ "method": "isPrime", "number": 1.3e1 }"#;
+        let output = r#"{"method":"isPrime","prime":true}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_max_number_digits() {
+        let handler = test_handler();
+        let request = |number: &str| format!(r#"{{"method":"isPrime","number":{number}}}"#);
+
+        // the default limit is 1000 digits
+        let under = format!("1{}", "0".repeat(999));
+        let over = format!("1{}", "0".repeat(1000));
+
+        assert!(handler.handle(&request(&under)).is_ok());
+        assert!(handler.handle(&request("1e999")).is_ok());
+
+        for number in [over.as_str(), "1e1000", "1e99999999999"] {
+            assert!(
+                matches!(
+                    handler.handle(&request(number)),
+                    Err(PrimeTimeError::MalformedRequest(_))
+                ),
+                "{number}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_handle_request_string() {
+        let input = r#"{ "method": "isPrime", "number": "6017832" }"#;
+
+        assert!(matches!(
+            handle_request(input),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_request_number_wrong_type() {
+        let handler = RequestHandler::new(&ServerConfig {
+            structured_errors: true,
+            sieve_limit: 0,
+            ..Default::default()
+        });
+
+        for (number, kind) in [("null", "null"), ("true", "a boolean"), ("[]", "an array")] {
+            let error = handler
+                .handle(&format!(r#"{{"method":"isPrime","number":{number}}}"#))
+                .unwrap_err();
+            assert!(
+                matches!(error, PrimeTimeError::MalformedRequest(_)),
+                "{number}"
+            );
+
+            let response: ErrorResponse =
+                serde_json::from_str(&handler.error_line(&error)).unwrap();
+            assert_eq!(response.error, "invalid_request");
+            assert_eq!(
+                response.detail,
+                format!("Malformed request: number must be a JSON number, not {kind}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_handle_request_method_wrong_type() {
+        for strict_fields in [false, true] {
+            let handler = RequestHandler::new(&ServerConfig {
+                structured_errors: true,
+                strict_fields,
+                sieve_limit: 0,
+                ..Default::default()
+            });
+
+            for (method, kind) in [("123", "a number"), ("null", "null")] {
+                let error = handler
+                    .handle(&format!(r#"{{"method":{method},"number":7}}"#))
+                    .unwrap_err();
+
+                let response: ErrorResponse =
+                    serde_json::from_str(&handler.error_line(&error)).unwrap();
+                assert_eq!(response.error, "invalid_request");
+                assert_eq!(
+                    response.detail,
+                    format!("Malformed request: method must be a string, not {kind}")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_handle_request_invalid_json() {
+        assert!(matches!(
+            handle_request("{ not json"),
+            Err(PrimeTimeError::DeserializeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_request_wrong_method() {
+        let input = r#"{ "method": "isFoo", "number": 7 }"#;
+
+        assert!(matches!(
+            handle_request(input),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_request_missing_method() {
+        let input = r#"{ "number": 7 }"#;
+
+        assert!(matches!(
+            handle_request(input),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_request_ping() {
+        let input = r#"{ "method": "ping" }"#;
+        let output = r#"{"method":"ping","ok":true}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_ping_extra_fields() {
+        let input = r#"{ "method": "ping", "number": "seven", "yolo": "swag" }"#;
+        let output = r#"{"method":"ping","ok":true}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_stats() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            stats_method: true,
+            ..Default::default()
+        });
+
+        for number in [2, 3, 4, 7, 9] {
+            let input = format!(r#"{{"method":"isPrime","number":{number}}}"#);
+            handler.handle(&input).unwrap();
+        }
+
+        let output: Value =
+            serde_json::from_str(&handler.handle(r#"{"method":"stats"}"#).unwrap()).unwrap();
+
+        assert_eq!(output["method"], "stats");
+        assert_eq!(output["requests"], 5);
+        assert_eq!(output["primes"], 3);
+        assert_eq!(output["composites"], 2);
+        assert_eq!(output["malformed"], 0);
+        assert_eq!(output["malformed_reasons"]["invalid_json"], 0);
+        assert!(output["uptime_seconds"].is_u64());
+    }
+
+    #[test]
+    fn test_stats_uptime_increases() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 0,
+            stats_method: true,
+            ..Default::default()
+        });
+        let stats = || -> Value {
+            serde_json::from_str(&handler.handle(r#"{"method":"stats"}"#).unwrap()).unwrap()
+        };
+
+        let before = stats();
+        std::thread::sleep(Duration::from_millis(1100));
+        let after = stats();
+
+        let uptime = |stats: &Value| stats["uptime_seconds"].as_u64().unwrap();
+        assert!(uptime(&after) > uptime(&before));
+        assert_eq!(after["started_at"], before["started_at"]);
+        assert!(after["started_at"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_stats_disabled_by_default() {
+        assert!(matches!(
+            test_handler().handle(r#"{"method":"stats"}"#),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    fn factorize_handler() -> RequestHandler {
+        RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            factorize_method: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_handle_request_factorize() {
+        let input = r#"{ "method": "factorize", "number": 360 }"#;
+        let output = r#"{"method":"factorize","factors":[2,2,2,3,3,5]}"#;
+
+        assert_eq!(factorize_handler().handle(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_factorize_disabled_by_default() {
+        assert!(matches!(
+            handle_request(r#"{"method":"factorize","number":360}"#),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_factorize_limits() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            factorize_method: true,
+            max_factorize_digits: 20,
+            ..Default::default()
+        });
+        let factorize = |number: &str| {
+            handler.handle(&format!(r#"{{"method":"factorize","number":{number}}}"#))
+        };
+
+        // 2^64 has 20 digits, 2^67 has 21
+        assert!(factorize("18446744073709551616").is_ok());
+        assert!(matches!(
+            factorize("147573952589676412928"),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+
+        // two 8 digit factors are found, two 20 digit ones take too long
+        assert_eq!(
+            factorize("600000110000003").unwrap(),
+            r#"{"method":"factorize","factors":[20000003,30000001]}"#
+        );
+        let handler = factorize_handler();
+        assert!(matches!(
+            handler.handle(
+                r#"{"method":"factorize","number":100000000000000001380000000000000004437}"#
+            ),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_request_factorize_edge_cases() {
+        let handler = factorize_handler();
+
+        // a perfect power
+        let input = r#"{ "method": "factorize", "number": 1024 }"#;
+        let output = r#"{"method":"factorize","factors":[2,2,2,2,2,2,2,2,2,2]}"#;
+        assert_eq!(handler.handle(input).unwrap(), output);
+
+        // a prime
+        let input = r#"{ "method": "factorize", "number": 7919 }"#;
+        let output = r#"{"method":"factorize","factors":[7919]}"#;
+        assert_eq!(handler.handle(input).unwrap(), output);
+
+        let input = r#"{ "method": "factorize", "number": 1 }"#;
+        let output = r#"{"method":"factorize","factors":[]}"#;
+        assert_eq!(handler.handle(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_factorize_large_factors() {
+        // factors that don't fit in a u64 are still JSON integers
+        let input = r#"{ "method": "factorize", "number": 55340232221128654887 }"#;
+        let output = r#"{"method":"factorize","factors":[3,18446744073709551629]}"#;
+
+        assert_eq!(factorize_handler().handle(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_factorize_malformed() {
+        for number in ["-12", "0", "12.5"] {
+            let input = format!(r#"{{ "method": "factorize", "number": {number} }}"#);
+
+            assert!(matches!(
+                factorize_handler().handle(&input),
+                Err(PrimeTimeError::MalformedRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_handle_request_next_prime() {
+        let input = r#"{ "method": "nextPrime", "number": 100 }"#;
+        let output = r#"{"method":"nextPrime","next":101}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_next_prime_edge_cases() {
+        let handler = test_handler();
+        let next = |n: i64| handler.next_prime(&BigInt::from(n));
+
+        // strictly greater, even when the input is prime
+        assert_eq!(next(7), BigInt::from(11));
+        assert_eq!(next(2), BigInt::from(3));
+
+        // everything below 2 goes to 2
+        assert_eq!(next(1), BigInt::from(2));
+        assert_eq!(next(0), BigInt::from(2));
+        assert_eq!(next(-100), BigInt::from(2));
+
+        // crossing the sieve limit of 1000
+        assert_eq!(next(997), BigInt::from(1009));
+    }
+
+    #[test]
+    fn test_composite_factor() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            composite_factor: true,
+            ..Default::default()
+        });
+        let answer = |number: &str| {
+            let input = format!(r#"{{"method":"isPrime","number":{number}}}"#);
+            handler.handle(&input).unwrap()
+        };
+
+        assert_eq!(
+            answer("15"),
+            "{\"method\":\"isPrime\",\"prime\":false,\"factor\":3}"
+        );
+        assert_eq!(answer("7"), "{\"method\":\"isPrime\",\"prime\":true}");
+
+        // numbers that aren't prime without being composite have no factor
+        for number in ["1", "0", "-15", "15.5"] {
+            assert_eq!(
+                answer(number),
+                "{\"method\":\"isPrime\",\"prime\":false}",
+                "{number}"
+            );
+        }
+
+        // off by default
+        let input = r#"{"method":"isPrime","number":15}"#;
+        assert_eq!(
+            test_handler().handle(input).unwrap(),
+            "{\"method\":\"isPrime\",\"prime\":false}"
+        );
+    }
+
+    #[test]
+    fn test_echo_number() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            echo_number: true,
+            ..Default::default()
+        });
+
+        // 2^521 - 1 is a Mersenne prime, far past what a float could hold
+        let mersenne: BigInt = (BigInt::from(1) << 521) - 1;
+        let mersenne = mersenne.to_string();
+        for (number, prime) in [("7", true), (mersenne.as_str(), true), ("-7", false)] {
+            let input = format!(r#"{{"method":"isPrime","number":{number}}}"#);
+            let output = format!(r#"{{"method":"isPrime","prime":{prime},"number":{number}}}"#);
+            assert_eq!(handler.handle(&input).unwrap(), output);
+
+            let response: Response = serde_json::from_str(&output).unwrap();
+            assert_eq!(
+                response.number,
+                Some(RequestNumber::BigInt(number.parse().unwrap()))
+            );
+        }
+
+        // floats come back as floats
+        let input = r#"{"method":"isPrime","number":7.0}"#;
+        assert_eq!(
+            handler.handle(input).unwrap(),
+            r#"{"method":"isPrime","prime":false,"number":7.0}"#
+        );
+    }
+
+    #[test]
+    fn test_number_bits_and_digits() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            number_bits: true,
+            number_digits: true,
+            factorize_method: true,
+            ..Default::default()
+        });
+        let answer = |request: &str| -> serde_json::Value {
+            serde_json::from_str(&handler.handle(request).unwrap()).unwrap()
+        };
+
+        let two_to_64: BigInt = BigInt::from(1) << 64;
+        for (number, bits, digits) in [
+            ("0", 0, 1),
+            ("7", 3, 1),
+            ("-255", 8, 3),
+            ("256", 9, 3),
+            (&two_to_64.to_string(), 65, 20),
+            (&(two_to_64 - 1u32).to_string(), 64, 20),
+            (&format!("1{}", "0".repeat(999)), 3319, 1000),
+        ] {
+            let response = answer(&format!(r#"{{"method":"isPrime","number":{number}}}"#));
+            assert_eq!(response["bits"], bits, "{number}");
+            assert_eq!(response["digits"], digits, "{number}");
+        }
+
+        let response = answer(r#"{"method":"factorize","number":1000}"#);
+        assert_eq!(response["bits"], 10);
+        assert_eq!(response["digits"], 4);
+
+        // floats have no bit length
+        let response = answer(r#"{"method":"isPrime","number":7.5}"#);
+        assert!(response.get("bits").is_none() && response.get("digits").is_none());
+
+        // each is off unless asked for
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            number_bits: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            handler
+                .handle(r#"{"method":"isPrime","number":7}"#)
+                .unwrap(),
+            r#"{"method":"isPrime","prime":true,"bits":3}"#
+        );
+    }
+
+    #[test]
+    fn test_decimal_digits() {
+        for n in [1u64, 9, 10, 99, 100, 999_999, 1_000_000, u64::MAX] {
+            assert_eq!(
+                decimal_digits(&BigUint::from(n)),
+                n.to_string().len() as u64,
+                "{n}"
+            );
+        }
+
+        let mut n = BigUint::from(1u32);
+        for digits in 1..=400 {
+            assert_eq!(decimal_digits(&n), digits);
+            assert_eq!(decimal_digits(&(&n - 1u32)), (digits - 1).max(1));
+            n *= 10u32;
+        }
+    }
+
+    #[test]
+    fn test_pretty_responses() {
+        let handler = RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            pretty_responses: true,
+            structured_errors: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            handler
+                .handle(r#"{"method":"isPrime","number":7}"#)
+                .unwrap(),
+            "{\n  \"method\": \"isPrime\",\n  \"prime\": true\n}"
+        );
+        assert_eq!(
+            handler.handle(r#"[{"method":"ping"}]"#).unwrap(),
+            "[\n  {\n    \"method\": \"ping\",\n    \"ok\": true\n  }\n]"
+        );
+
+        let error = handler
+            .handle(r#"{"method":"isFoo","number":7}"#)
+            .unwrap_err();
+        assert!(handler
+            .error_line(&error)
+            .starts_with("{\n  \"error\": \"invalid_request\","));
+    }
+
+    #[test]
+    fn test_count_primes_below() {
+        let handler = test_handler();
+
+        for (number, count) in [(2, 0), (3, 1), (100, 25), (0, 0), (1000, 168)] {
+            let input = format!(r#"{{"method":"countPrimesBelow","number":{number}}}"#);
+            let output = format!("{{\"method\":\"countPrimesBelow\",\"count\":{count}}}");
+
+            assert_eq!(handler.handle(&input).unwrap(), output, "{number}");
+        }
+    }
+
+    #[test]
+    fn test_count_primes_below_malformed() {
+        let handler = test_handler();
+
+        // numbers above the sieve limit of 1000 aren't sieved for the request
+        for number in ["12.5", "-3", "1001", "10000", "100000000000", "1e30"] {
+            let input = format!(r#"{{"method":"countPrimesBelow","number":{number}}}"#);
+
+            assert!(
+                matches!(
+                    handler.handle(&input),
+                    Err(PrimeTimeError::MalformedRequest(_))
+                ),
+                "{number}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_next_prime_limit() {
+        let handler = test_handler();
+        let next_prime = |number: &str| {
+            handler.handle(&format!(r#"{{"method":"nextPrime","number":{number}}}"#))
+        };
+
+        // the default limit is 100 digits
+        assert!(next_prime(&format!("1{}", "0".repeat(99))).is_ok());
+        assert!(matches!(
+            next_prime(&format!("1{}", "0".repeat(100))),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+        assert!(matches!(
+            next_prime(&format!("-1{}", "0".repeat(100))),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_next_prime_across_large_gap() {
+        // there are no primes between 370261 and 370373
+        let input = r#"{ "method": "nextPrime", "number": 370261 }"#;
+        let output = r#"{"method":"nextPrime","next":370373}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_next_prime_malformed() {
+        let input = r#"{ "method": "nextPrime", "number": 12.5 }"#;
+
+        assert!(matches!(
+            handle_request(input),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_request_batch() {
+        let input =
+            r#"[{ "method": "isPrime", "number": 7 }, { "method": "isPrime", "number": 8 }]"#;
+        let output = r#"[{"method":"isPrime","prime":true},{"method":"isPrime","prime":false}]"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_batch_with_malformed_element() {
+        let input =
+            r#"[{ "method": "isPrime", "number": 7 }, { "method": "isPrime", "number": "8" }]"#;
+
+        assert!(handle_request(input).is_err());
+    }
+
+    #[test]
+    fn test_handle_request_batch_of_arrays() {
+        let handler = test_handler();
+
+        for input in [
+            r#"[["isPrime",7]]"#.to_string(),
+            r#"["isPrime",7]"#.to_string(),
+            // the digit limit applies to arrays too
+            format!(r#"[["isPrime",1{}]]"#, "0".repeat(1000)),
+        ] {
+            assert!(
+                matches!(
+                    handler.handle(&input),
+                    Err(PrimeTimeError::MalformedRequest(_))
+                ),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_concatenated_requests_strict_by_default() {
+        let input = r#"{"method":"isPrime","number":7}{"method":"isPrime","number":8}"#;
+
+        assert!(matches!(
+            handle_request(input),
+            Err(PrimeTimeError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_trailing_data_rejected() {
+        for input in [
+            r#"{"method":"isPrime","number":7} extra"#,
+            r#"{"method":"isPrime","number":7} ,"#,
+            r#"{"method":"isPrime","number":7} 8"#,
+        ] {
+            let result = handle_request(input);
+            assert!(
+                matches!(&result, Err(PrimeTimeError::MalformedRequest(e)) if e.contains("column 33")),
+                "{input}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_trailing_whitespace_allowed() {
+        let input = "{\"method\":\"isPrime\",\"number\":7} \t  \r\n";
+
+        assert_eq!(
+            handle_request(input).unwrap(),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+    }
+
+    #[test]
+    fn test_concatenated_requests() {
+        let handler = RequestHandler::new(&ServerConfig {
+            concatenated_requests: true,
+            sieve_limit: 100,
+            ..Default::default()
+        });
+
+        let input =
+            r#"{"method":"isPrime","number":7}{"method":"isPrime","number":8} [{"method":"ping"}]"#;
+        let output = [
+            r#"{"method":"isPrime","prime":true}"#,
+            r#"{"method":"isPrime","prime":false}"#,
+            r#"[{"method":"ping","ok":true}]"#,
+        ]
+        .join("\n");
+        assert_eq!(handler.handle(input).unwrap(), output);
+
+        // the responses are separated by the configured delimiter
+        let handler = RequestHandler::new(&ServerConfig {
+            concatenated_requests: true,
+            sieve_limit: 100,
+            delimiter: 0,
+            ..Default::default()
+        });
+        assert_eq!(handler.handle(input).unwrap(), output.replace('\n', "\0"));
+
+        // one bad request spoils the line
+        let input = r#"{"method":"isPrime","number":7}{"method":"isPrime"}"#;
+        assert!(handler.handle(input).is_err());
+
+        assert!(handler.handle("\n").is_err());
+    }
+
+    #[test]
+    fn test_handle_request_empty_batch() {
+        assert_eq!(handle_request("[]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn test_request_handler_cache() {
+        let config = ServerConfig {
+            sieve_limit: 100,
+            ..Default::default()
+        };
+        let handler = RequestHandler::new(&config);
+        let input = r#"{ "method": "isPrime", "number": 178417 }"#;
+
+        let first = handler.handle(input).unwrap();
+        let second = handler.handle(input).unwrap();
+
+        assert_eq!(first, second);
+        let cache = handler.cache.as_ref().unwrap();
+        assert_eq!(cache.get(&BigInt::from(178417)), Some(true));
+    }
+
+    #[test]
+    fn test_request_handler_sieve_skips_cache() {
+        let handler = RequestHandler::new(&ServerConfig::default());
+        let input = r#"{ "method": "isPrime", "number": 7919 }"#;
+
+        let output = r#"{"method":"isPrime","prime":true}"#;
+
+        assert_eq!(handler.handle(input).unwrap(), output);
+        let cache = handler.cache.as_ref().unwrap();
+        assert_eq!(cache.get(&BigInt::from(7919)), None);
+    }
+
+    // a cache backend recording how it is used
+    #[derive(Debug, Default)]
+    struct MockCache {
+        entries: std::sync::Mutex<std::collections::HashMap<BigInt, bool>>,
+        puts: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ResultCache for MockCache {
+        fn get(&self, n: &BigInt) -> Option<bool> {
+            self.entries.lock().unwrap().get(n).copied()
+        }
+
+        fn put(&self, n: &BigInt, prime: bool) {
+            self.puts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.entries.lock().unwrap().insert(n.clone(), prime);
+        }
+    }
+
+    #[test]
+    fn test_pluggable_result_cache() {
+        let cache = Arc::new(MockCache::default());
+        let config = ServerConfig {
+            sieve_limit: 0,
+            result_cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        let handler = RequestHandler::new(&config);
+        let answer = |n: u64| {
+            let input = format!(r#"{{"method":"isPrime","number":{n}}}"#);
+            handler.handle(&input).unwrap()
+        };
+
+        // computed once, then answered from the backend
+        answer(178417);
+        answer(178417);
+        assert_eq!(cache.get(&BigInt::from(178417)), Some(true));
+        assert_eq!(cache.puts.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // whatever the backend holds is trusted
+        cache.put(&BigInt::from(1_000_001), true);
+        assert_eq!(answer(1_000_001), "{\"method\":\"isPrime\",\"prime\":true}");
+    }
+
+    #[test]
+    fn test_request_handler_metrics() {
+        let handler = test_handler();
+
+        handler
+            .handle(r#"{"method":"isPrime","number":7}"#)
+            .unwrap();
+        handler
+            .handle(r#"{"method":"isPrime","number":8}"#)
+            .unwrap();
+
+        assert_eq!(handler.metrics().requests(), 2);
+        assert_eq!(handler.metrics().primes(), 1);
+        assert_eq!(handler.metrics().composites(), 1);
+    }
+
+    #[test]
+    fn test_error_line() {
+        let error = handle_request("not json").unwrap_err();
+
+        assert_eq!(test_handler().error_line(&error), "Invalid JSON");
+    }
+
+    #[test]
+    fn test_structured_error_line() {
+        let handler = RequestHandler::new(&ServerConfig {
+            structured_errors: true,
+            sieve_limit: 0,
+            ..Default::default()
+        });
+
+        let error = handler
+            .handle(r#"{"method":"isFoo","number":7}"#)
+            .unwrap_err();
+        let line = handler.error_line(&error);
+        assert!(!line.ends_with('\n'));
+
+        let response: ErrorResponse = serde_json::from_str(&line).unwrap();
+        assert_eq!(response.error, "invalid_request");
+        assert_eq!(
+            response.detail,
+            r#"Malformed request: unknown method "isFoo""#
+        );
+
+        let line = handler.error_line(&PrimeTimeError::Timeout(Duration::from_secs(1)));
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["error"], "timeout");
+        assert_eq!(response.as_object().unwrap().len(), 2);
+    }
+
+    // answers that every odd number is prime and remembers what it was asked
+    #[derive(Debug, Default)]
+    struct MockChecker {
+        queried: std::sync::Mutex<Vec<BigInt>>,
+    }
+
+    impl crate::PrimalityChecker for Arc<MockChecker> {
+        fn is_prime(&self, n: &BigInt) -> bool {
+            self.queried.lock().unwrap().push(n.clone());
+            n.is_odd()
+        }
+    }
+
+    // Small and well known numbers with the answer every layer has to give
+    const EDGE_NUMBERS: [(&str, bool); 16] = [
+        ("0", false),
+        ("1", false),
+        ("2", true),
+        ("3", true),
+        ("4", false),
+        ("-0", false),
+        ("-1", false),
+        ("-2", false),
+        ("-3", false),
+        ("-7", false),
+        ("97", true),
+        ("561", false),
+        ("7919", true),
+        ("1000003", true),
+        ("4294967297", false),
+        ("170141183460469231731687303715884105727", true),
+    ];
+
+    #[test]
+    fn test_edge_numbers_through_every_layer() {
+        // the sieve answers small numbers, a handler without one sends every
+        // number to the checker, with and without the cache in front
+        let handlers = [
+            ("sieve", test_handler()),
+            (
+                "checker",
+                Arc::new(RequestHandler::new(&ServerConfig {
+                    sieve_limit: 0,
+                    cache_size: 0,
+                    ..Default::default()
+                })),
+            ),
+            (
+                "cache",
+                Arc::new(RequestHandler::new(&ServerConfig {
+                    sieve_limit: 0,
+                    ..Default::default()
+                })),
+            ),
+        ];
+
+        for (number, prime) in EDGE_NUMBERS {
+            let request = format!(r#"{{"method":"isPrime","number":{number}}}"#);
+            let expected = format!("{{\"method\":\"isPrime\",\"prime\":{prime}}}");
+            let n: BigInt = number.parse().unwrap();
+
+            assert_eq!(
+                crate::is_number_prime(&n),
+                prime,
+                "is_number_prime({number})"
+            );
+            assert_eq!(handle_request(&request).unwrap(), expected, "{number}");
+
+            for (name, handler) in &handlers {
+                assert_eq!(handler.is_prime(&n), prime, "{name}: {number}");
+                assert_eq!(
+                    handler.handle(&request).unwrap(),
+                    expected,
+                    "{name}: {number}"
+                );
+            }
+        }
+    }
+
+    // Ground truth for the property test below, slow but obviously right
+    fn is_prime_trial(n: i64) -> bool {
+        if n < 2 {
+            return false;
+        }
+
+        (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+    }
+
+    #[test]
+    fn test_primality_matches_trial_division() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // a fixed seed keeps failures reproducible
+        let mut rng = StdRng::seed_from_u64(0x5EED);
+        let checker = RequestHandler::new(&ServerConfig {
+            sieve_limit: 0,
+            cache_size: 0,
+            ..Default::default()
+        });
+
+        // mostly small numbers where the sieve and the checker meet, plus
+        // some beyond the sieve that still divide quickly
+        let mut numbers: Vec<i64> = (0..2000).map(|_| rng.gen_range(-1000..200_000)).collect();
+        numbers.extend((0..300).map(|_| rng.gen_range(1 << 31..1 << 34)));
+
+        for n in numbers {
+            let expected = is_prime_trial(n);
+            let big = BigInt::from(n);
+            let request = format!(r#"{{"method":"isPrime","number":{n}}}"#);
+            let response = format!("{{\"method\":\"isPrime\",\"prime\":{expected}}}");
+
+            assert_eq!(crate::is_number_prime(&big), expected, "{n}");
+            assert_eq!(checker.is_prime(&big), expected, "{n}");
+            assert_eq!(handle_request(&request).unwrap(), response, "{n}");
+        }
+    }
+
+    #[test]
+    fn test_primality_span() {
+        let captured = crate::testing::Captured::default();
+        let handler = test_handler();
+
+        tracing::subscriber::with_default(captured.json_subscriber(), || {
+            let span = tracing::info_span!("Connection", client = "127.0.0.1:5000");
+            let _entered = span.enter();
+
+            handler
+                .handle(r#"{"method":"isPrime","number":1000003}"#)
+                .unwrap();
+        });
+
+        let checked: Value = captured
+            .output()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &Value| line["fields"]["message"] == "Checked primality")
+            .unwrap();
+
+        // nested under the connection, with the size of the number
+        let spans = checked["spans"].as_array().unwrap();
+        assert_eq!(spans[0]["name"], "Connection");
+        assert_eq!(spans[1]["name"], "primality");
+        assert_eq!(spans[1]["bits"], 20);
+        assert!(spans[1]["elapsed"].is_string());
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let checker = Arc::new(MockChecker::default());
+        let config = ServerConfig::builder()
+            .sieve_limit(10)
+            .warm_cache_below(100)
+            .primality(checker.clone())
+            .build()
+            .unwrap();
+        let handler = RequestHandler::new(&config);
+
+        // 11 to 97
+        assert_eq!(handler.warm_up(), 21);
+
+        let prime = r#"{"method":"isPrime","prime":true}"#;
+        let output = handler.handle(r#"{"method":"isPrime","number":97}"#);
+        assert_eq!(output.unwrap(), prime);
+        assert!(checker.queried.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_primality_checker() {
+        let checker = Arc::new(MockChecker::default());
+        let config = ServerConfig::builder()
+            .sieve_limit(10)
+            .cache_size(0)
+            .primality(checker.clone())
+            .build()
+            .unwrap();
+        let handler = RequestHandler::new(&config);
+
+        for number in [7, 15, 16] {
+            handler
+                .handle(&format!(r#"{{"method":"isPrime","number":{number}}}"#))
+                .unwrap();
+        }
+
+        let prime = r#"{"method":"isPrime","prime":true}"#;
+        let output = handler.handle(r#"{"method":"isPrime","number":21}"#);
+        assert_eq!(output.unwrap(), prime);
+
+        // 7 is answered by the sieve without asking the checker
+        let queried = checker.queried.lock().unwrap();
+        assert_eq!(*queried, [15, 16, 21].map(BigInt::from));
+    }
+
+    #[test]
+    fn test_factorize_uses_the_checker() {
+        let checker = Arc::new(MockChecker::default());
+        let config = ServerConfig::builder()
+            .sieve_limit(10)
+            .factorize_method(true)
+            .primality(checker.clone())
+            .build()
+            .unwrap();
+        let handler = RequestHandler::new(&config);
+
+        // 11 * 13 is past trial division by the sieve's primes, and the
+        // checker calls every odd number prime
+        let output = handler.handle(r#"{"method":"factorize","number":143}"#);
+        assert_eq!(output.unwrap(), r#"{"method":"factorize","factors":[143]}"#);
+        assert_eq!(*checker.queried.lock().unwrap(), [BigInt::from(143)]);
+    }
+
+    #[test]
+    fn test_integral_floats_as_int() {
+        let config = ServerConfig {
+            treat_integral_floats_as_int: true,
+            sieve_limit: 100,
+            ..Default::default()
+        };
+        let handler = RequestHandler::new(&config);
+
+        let prime = r#"{"method":"isPrime","prime":true}"#;
+        let composite = r#"{"method":"isPrime","prime":false}"#;
+
+        let input = r#"{ "method": "isPrime", "number": 7.0 }"#;
+        assert_eq!(handler.handle(input).unwrap(), prime);
+
+        let input = r#"{ "method": "isPrime", "number": 7.5 }"#;
+        assert_eq!(handler.handle(input).unwrap(), composite);
+
+        // too large to be represented exactly. Without an exponent the
+        // literal stays a float, with one it would be read as an integer.
+        let input = r#"{ "method": "isPrime", "number": 18014398509481985.0 }"#;
+        assert_eq!(handler.handle(input).unwrap(), composite);
+        assert_eq!(integral_float(18014398509481985.0), None);
+        assert_eq!(
+            integral_float(9007199254740992.0),
+            Some(BigInt::from(1u64 << 53))
+        );
+    }
+
+    #[test]
+    fn test_integral_floats_off_by_default() {
+        let input = r#"{ "method": "isPrime", "number": 7.0 }"#;
+        let output = r#"{"method":"isPrime","prime":false}"#;
+
+        assert_eq!(handle_request(input).unwrap(), output);
+    }
+}
@@ -0,0 +1,650 @@
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpListener,
+    time::timeout,
+};
+
+use crate::{
+    server::{
+        bind_with_config, handle_line, serve_protocol, shutdown_signal, AcceptControl, WireProtocol,
+    },
+    PrimeTimeError, RequestHandler, ServerConfig,
+};
+
+// Longest request or header line accepted, including its line ending
+const MAX_HEAD_LINE_BYTES: usize = 8192;
+
+// Most headers accepted on one request
+const MAX_HEADERS: usize = 100;
+
+// An HTTP status code and its reason phrase
+type Status = (u16, &'static str);
+
+const OK: Status = (200, "OK");
+const BAD_REQUEST: Status = (400, "Bad Request");
+const NOT_FOUND: Status = (404, "Not Found");
+const METHOD_NOT_ALLOWED: Status = (405, "Method Not Allowed");
+const LENGTH_REQUIRED: Status = (411, "Length Required");
+const PAYLOAD_TOO_LARGE: Status = (413, "Payload Too Large");
+const HEADERS_TOO_LARGE: Status = (431, "Request Header Fields Too Large");
+const INTERNAL_SERVER_ERROR: Status = (500, "Internal Server Error");
+const NOT_IMPLEMENTED: Status = (501, "Not Implemented");
+const SERVICE_UNAVAILABLE: Status = (503, "Service Unavailable");
+
+/// Start an HTTP/1.1 server on `socket`. Clients POST a request to
+/// `/isPrime` with the same JSON body they would send as a line, and get the
+/// response line back as the body.
+///
+/// ```text
+/// POST /isPrime HTTP/1.1
+/// Content-Type: application/json
+/// Content-Length: 31
+///
+/// {"method":"isPrime","number":7}
+/// ```
+///
+/// Malformed bodies get a 400 with the usual malformed response, and a
+/// request that times out gets a 503. Connections are kept alive between
+/// requests unless the client asks otherwise.
+///
+/// Connections are accepted and limited as they are for the line protocol:
+/// [`ServerConfig::max_connections`], [`ServerConfig::rate_limit`] and
+/// [`ServerConfig::shutdown_grace`] all apply.
+pub async fn run_http(socket: SocketAddr) -> Result<(), PrimeTimeError> {
+    run_http_with_config(ServerConfig::builder().addr(socket).build()?).await
+}
+
+/// Like [`run_http`], but with the given configuration. Settings that only
+/// make sense for the line protocol, like the framing, are ignored.
+pub async fn run_http_with_config(config: ServerConfig) -> Result<(), PrimeTimeError> {
+    config.validate()?;
+    let (listener, _) = bind_with_config(&config)?;
+
+    serve_http(listener, config).await
+}
+
+/// Answer HTTP requests on `listener` until SIGINT or SIGTERM is received
+pub async fn serve_http(listener: TcpListener, config: ServerConfig) -> Result<(), PrimeTimeError> {
+    serve_http_until(listener, config, shutdown_signal()).await
+}
+
+// Answer HTTP requests until the shutdown future resolves
+async fn serve_http_until(
+    listener: TcpListener,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+) -> Result<(), PrimeTimeError> {
+    serve_protocol(
+        vec![listener],
+        config,
+        shutdown,
+        AcceptControl::default(),
+        WireProtocol::Http,
+    )
+    .await
+}
+
+// A request read off a connection
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: String,
+    keep_alive: bool,
+}
+
+// Why a request couldn't be read. Either way the connection is closed.
+enum ReadError {
+    // the client sent something that isn't a request we can read, and is
+    // told so before the connection is closed
+    Status(Status),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// Answer requests on one connection until either side closes it
+pub(crate) async fn handle_http_connection<S>(
+    stream: S,
+    peer: Option<IpAddr>,
+    config: Arc<ServerConfig>,
+    handler: Arc<RequestHandler>,
+) -> Result<(), PrimeTimeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let read = timeout(config.idle_timeout, read_http_request(&mut reader, &config)).await;
+
+        let request = match read {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(ReadError::Status(status))) => {
+                tracing::info!(status = status.0, "Unreadable HTTP request");
+                let response = http_response(status, "text/plain", status.1, false);
+                writer.write_all(&response).await?;
+                return Ok(());
+            }
+            Ok(Err(ReadError::Io(e))) => return Err(e.into()),
+        };
+
+        // slow down clients sending requests too quickly
+        if let (Some(limiter), Some(ip)) = (handler.rate_limiter(), peer) {
+            let wait = limiter.acquire(ip);
+
+            if !wait.is_zero() {
+                tracing::debug!(?wait, "Rate limited");
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let keep_alive = request.keep_alive;
+        let response = answer_http(request, &config, &handler).await;
+        writer.write_all(&response).await?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Read a request head and its body, `None` if the client closed the
+// connection before starting another
+async fn read_http_request<R>(
+    reader: &mut R,
+    config: &ServerConfig,
+) -> Result<Option<HttpRequest>, ReadError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let Some(request_line) = read_head_line(reader).await? else {
+        return Ok(None);
+    };
+
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ReadError::Status(BAD_REQUEST));
+    };
+
+    // HTTP/1.0 closes after each response unless asked not to
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(ReadError::Status(BAD_REQUEST)),
+    };
+
+    let mut content_length = None;
+    for headers in 0.. {
+        let line = read_head_line(reader)
+            .await?
+            .ok_or(ReadError::Status(BAD_REQUEST))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(ReadError::Status(HEADERS_TOO_LARGE));
+        }
+
+        let (name, value) = line.split_once(':').ok_or(ReadError::Status(BAD_REQUEST))?;
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value
+                .parse::<usize>()
+                .map_err(|_| ReadError::Status(BAD_REQUEST))?;
+
+            // lengths that disagree would let a proxy in front of the server
+            // split the stream into different requests than it does
+            if content_length.is_some_and(|previous| previous != length) {
+                return Err(ReadError::Status(BAD_REQUEST));
+            }
+            content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(ReadError::Status(NOT_IMPLEMENTED));
+        } else if name.eq_ignore_ascii_case("connection") {
+            keep_alive = match value.to_ascii_lowercase().as_str() {
+                "close" => false,
+                "keep-alive" => true,
+                _ => keep_alive,
+            };
+        }
+    }
+
+    let length = match content_length {
+        Some(length) => length,
+        None if method == "POST" => return Err(ReadError::Status(LENGTH_REQUIRED)),
+        None => 0,
+    };
+    if length > config.max_line_bytes {
+        return Err(ReadError::Status(PAYLOAD_TOO_LARGE));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    // a body that isn't UTF-8 can't be JSON, it's answered as malformed
+    let body = String::from_utf8(body).unwrap_or_default();
+
+    Ok(Some(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+        keep_alive,
+    }))
+}
+
+// Read a line of the request head without its line ending, `None` if the
+// connection closed before any of it arrived
+async fn read_head_line<R>(reader: &mut R) -> Result<Option<String>, ReadError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_HEAD_LINE_BYTES as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(ReadError::Status(match read {
+            MAX_HEAD_LINE_BYTES => HEADERS_TOO_LARGE,
+            _ => BAD_REQUEST,
+        }));
+    }
+
+    let line = String::from_utf8(line).map_err(|_| ReadError::Status(BAD_REQUEST))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// Route a request and build the response to it
+async fn answer_http(
+    request: HttpRequest,
+    config: &ServerConfig,
+    handler: &Arc<RequestHandler>,
+) -> Vec<u8> {
+    let keep_alive = request.keep_alive;
+
+    if request.path != "/isPrime" {
+        return http_response(NOT_FOUND, "text/plain", NOT_FOUND.1, keep_alive);
+    }
+    if request.method != "POST" {
+        return http_response(
+            METHOD_NOT_ALLOWED,
+            "text/plain",
+            METHOD_NOT_ALLOWED.1,
+            keep_alive,
+        );
+    }
+
+    tracing::info!(received = ?request.body);
+
+    let mut body = request.body;
+    let (status, response) = match handle_line(handler, &mut body, config.request_timeout).await {
+        Ok(response) => (OK, response),
+        Err(e @ PrimeTimeError::Timeout(_)) => {
+            tracing::warn!("{}", e);
+            (SERVICE_UNAVAILABLE, handler.error_line(&e))
+        }
+        Err(
+            e @ (PrimeTimeError::WorkerFailed
+            | PrimeTimeError::JoinError(_)
+            | PrimeTimeError::IOError(_)),
+        ) => {
+            tracing::error!("Failed to answer request: {}", e);
+            (INTERNAL_SERVER_ERROR, handler.error_line(&e))
+        }
+        Err(e) => {
+            tracing::info!("Bad request: {}", e);
+            handler.metrics().record_malformed(&e);
+            (BAD_REQUEST, handler.error_line(&e))
+        }
+    };
+
+    tracing::info!(sending = ?response);
+
+    // the bare malformed response isn't JSON
+    let content_type = match status == OK || config.structured_errors {
+        true => "application/json",
+        false => "text/plain",
+    };
+
+    http_response(status, content_type, &response, keep_alive)
+}
+
+// A complete HTTP/1.1 response
+fn http_response(status: Status, content_type: &str, body: &str, keep_alive: bool) -> Vec<u8> {
+    let allow = match status {
+        METHOD_NOT_ALLOWED => "Allow: POST\r\n",
+        _ => "",
+    };
+    let connection = match keep_alive {
+        true => "",
+        false => "Connection: close\r\n",
+    };
+
+    format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         {allow}{connection}\
+         \r\n\
+         {body}",
+        status.0,
+        status.1,
+        body.len()
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    use tokio::net::TcpStream;
+
+    use crate::RateLimit;
+
+    // start a server with `config` on an ephemeral port, returning its
+    // address
+    async fn spawn_http(config: ServerConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = ServerConfig {
+            sieve_limit: 1000,
+            ..config
+        };
+        tokio::spawn(serve_http_until(listener, config, std::future::pending()));
+
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        BufReader::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    // start a server on an ephemeral port and connect to it
+    async fn http_pair() -> BufReader<TcpStream> {
+        connect(spawn_http(ServerConfig::default()).await).await
+    }
+
+    // send a raw request and read one response, giving its status line,
+    // headers and body
+    async fn exchange(
+        client: &mut BufReader<TcpStream>,
+        request: &str,
+    ) -> (String, String, String) {
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut status = String::new();
+        client.read_line(&mut status).await.unwrap();
+
+        let mut headers = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            headers.push_str(&line);
+        }
+
+        let mut body = vec![0; length];
+        client.read_exact(&mut body).await.unwrap();
+
+        (
+            status.trim_end().to_string(),
+            headers,
+            String::from_utf8(body).unwrap(),
+        )
+    }
+
+    fn post(body: &str) -> String {
+        format!(
+            "POST /isPrime HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_http_is_prime() {
+        let mut client = http_pair().await;
+
+        let (status, headers, body) =
+            exchange(&mut client, &post(r#"{"method":"isPrime","number":7}"#)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(headers.contains("Content-Type: application/json\r\n"));
+        assert_eq!(body, r#"{"method":"isPrime","prime":true}"#);
+
+        // the connection is kept alive for the next request
+        let (status, _, body) =
+            exchange(&mut client, &post(r#"{"method":"isPrime","number":8}"#)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, r#"{"method":"isPrime","prime":false}"#);
+    }
+
+    #[tokio::test]
+    async fn test_http_pretty_responses() {
+        let mut client = connect(
+            spawn_http(
+                ServerConfig::builder()
+                    .pretty_responses(true)
+                    .build()
+                    .unwrap(),
+            )
+            .await,
+        )
+        .await;
+
+        let (status, _, body) =
+            exchange(&mut client, &post(r#"{"method":"isPrime","number":7}"#)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "{\n  \"method\": \"isPrime\",\n  \"prime\": true\n}");
+
+        // the Content-Length frames it, so the next request still works
+        let (status, _, body) = exchange(&mut client, &post(r#"{"method":"ping"}"#)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "{\n  \"method\": \"ping\",\n  \"ok\": true\n}");
+    }
+
+    #[tokio::test]
+    async fn test_http_malformed_body() {
+        let mut client = http_pair().await;
+
+        let (status, _, body) = exchange(&mut client, &post("not json")).await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(body, "Invalid JSON");
+
+        // a bad body doesn't spoil the connection
+        let (status, _, _) = exchange(&mut client, &post(r#"{"method":"ping"}"#)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_http_routing() {
+        let mut client = http_pair().await;
+
+        let (status, headers, _) = exchange(
+            &mut client,
+            "GET /isPrime HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        assert!(headers.contains("Allow: POST\r\n"));
+
+        let (status, _, _) = exchange(
+            &mut client,
+            "POST /nextPrime HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}",
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_http_unreadable_requests() {
+        for (request, expected) in [
+            (
+                "POST /isPrime HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 411 Length Required",
+            ),
+            (
+                "POST /isPrime HTTP/1.1\r\nContent-Length: seven\r\n\r\n",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "POST /isPrime HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "HTTP/1.1 501 Not Implemented",
+            ),
+            (
+                "POST /isPrime HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n",
+                "HTTP/1.1 413 Payload Too Large",
+            ),
+            ("NONSENSE\r\n\r\n", "HTTP/1.1 400 Bad Request"),
+            (
+                "POST /isPrime HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 17\r\n\r\n{}",
+                "HTTP/1.1 400 Bad Request",
+            ),
+        ] {
+            let mut client = http_pair().await;
+
+            let (status, headers, _) = exchange(&mut client, request).await;
+            assert_eq!(status, expected, "{request:?}");
+            assert!(headers.contains("Connection: close\r\n"), "{request:?}");
+
+            // the connection is closed after saying why
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty(), "{request:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_connection_close() {
+        let mut client = http_pair().await;
+
+        let request = post(r#"{"method":"ping"}"#).replace("Host", "Connection: close\r\nHost");
+        let (status, headers, _) = exchange(&mut client, &request).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(headers.contains("Connection: close\r\n"));
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_http_repeated_content_length() {
+        let mut client = http_pair().await;
+
+        // the same length twice is unambiguous
+        let request = post(r#"{"method":"ping"}"#).replace("Host", "Content-Length: 17\r\nHost");
+        let (status, _, body) = exchange(&mut client, &request).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, r#"{"method":"ping","ok":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_http_max_connections() {
+        let addr = spawn_http(ServerConfig {
+            max_connections: 1,
+            ..Default::default()
+        })
+        .await;
+        let ping = post(r#"{"method":"ping"}"#);
+
+        let mut first = connect(addr).await;
+        let (status, _, _) = exchange(&mut first, &ping).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        // the second connection waits in the backlog while the first is open
+        let mut second = connect(addr).await;
+        let waiting = timeout(Duration::from_millis(200), exchange(&mut second, &ping)).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        let mut line = String::new();
+        second.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_http_rate_limit() {
+        let addr = spawn_http(ServerConfig {
+            rate_limit: Some(RateLimit {
+                requests_per_second: 5.0,
+                burst: 1,
+            }),
+            ..Default::default()
+        })
+        .await;
+        let mut client = connect(addr).await;
+        let ping = post(r#"{"method":"ping"}"#);
+
+        let started = Instant::now();
+        for _ in 0..2 {
+            let (status, _, _) = exchange(&mut client, &ping).await;
+            assert_eq!(status, "HTTP/1.1 200 OK");
+        }
+
+        // the second request waited for its token
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_http_shutdown_closes_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+
+        let config = ServerConfig {
+            shutdown_grace: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_http_until(listener, config, async {
+            let _ = stopped.await;
+            Ok(())
+        }));
+
+        // an idle keep-alive connection
+        let mut client = connect(addr).await;
+        let (status, _, _) = exchange(&mut client, &post(r#"{"method":"ping"}"#)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        stop.send(()).unwrap();
+        timeout(Duration::from_secs(5), server)
+            .await
+            .expect("the server stops once the grace period is over")
+            .unwrap()
+            .unwrap();
+
+        // and the connection was closed with it
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+// Lines waiting for the writer thread before logging blocks
+const QUEUED_LINES: usize = 128_000;
+
+/// How often a [`RollingFile`] starts a new file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    /// A file per hour, like `prime_time.log.2024-05-01-13`
+    Hourly,
+    /// A file per day, like `prime_time.log.2024-05-01`
+    #[default]
+    Daily,
+}
+
+impl Rotation {
+    // The suffix of the file written to `secs` after the epoch, in UTC
+    fn suffix(self, secs: u64) -> String {
+        let (year, month, day) = civil_date(secs / 86_400);
+
+        match self {
+            Self::Hourly => format!("{year:04}-{month:02}-{day:02}-{:02}", secs % 86_400 / 3600),
+            Self::Daily => format!("{year:04}-{month:02}-{day:02}"),
+        }
+    }
+}
+
+// The year, month and day some days after the epoch, from Howard Hinnant's
+// civil_from_days
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// A log file that starts afresh every hour or day.
+///
+/// Logs for `path` go to `path` with the UTC date, and hour if hourly,
+/// appended, so `prime_time.log` is written as `prime_time.log.2024-05-01`.
+/// Old files are left for the operator to clean up.
+#[derive(Debug)]
+pub struct RollingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    // the open file and the suffix it was opened with
+    current: Option<(String, File)>,
+}
+
+impl RollingFile {
+    /// Open the file for the current period, so a path that can't be
+    /// written fails here rather than on the first log line
+    pub fn new(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let mut file = Self {
+            path: path.into(),
+            rotation,
+            current: None,
+        };
+        file.file_at(now())?;
+
+        Ok(file)
+    }
+
+    // The file for `secs` after the epoch, opening it if the period changed
+    fn file_at(&mut self, secs: u64) -> io::Result<&mut File> {
+        let suffix = self.rotation.suffix(secs);
+
+        if !matches!(&self.current, Some((current, _)) if *current == suffix) {
+            let mut path = self.path.clone().into_os_string();
+            path.push(".");
+            path.push(&suffix);
+
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.current = Some((suffix, file));
+        }
+
+        Ok(&mut self.current.as_mut().expect("file was just opened").1)
+    }
+}
+
+// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file_at(now())?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((_, file)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+enum Message {
+    Line(Vec<u8>),
+    Shutdown,
+}
+
+/// Hands log lines to a thread that writes them out, so logging doesn't wait
+/// on the disk. Lines queue up until the [`LogGuard`] is dropped, so it has
+/// to be kept for as long as anything logs.
+///
+/// ```no_run
+/// use prime_time::log_file::{non_blocking, RollingFile, Rotation};
+///
+/// let file = RollingFile::new("prime_time.log", Rotation::Daily).unwrap();
+/// let (writer, _guard) = non_blocking(file);
+///
+/// tracing_subscriber::fmt().with_writer(writer).init();
+/// ```
+pub fn non_blocking(mut writer: impl Write + Send + 'static) -> (NonBlocking, LogGuard) {
+    let (lines, queue) = mpsc::sync_channel(QUEUED_LINES);
+
+    let thread = std::thread::Builder::new()
+        .name("prime_time-log-writer".to_string())
+        .spawn(move || {
+            // a line that can't be written has nowhere to be reported
+            while let Ok(Message::Line(line)) = queue.recv() {
+                let _ = writer.write_all(&line);
+            }
+            let _ = writer.flush();
+        })
+        .expect("failed to start log writer");
+
+    let guard = LogGuard {
+        lines: lines.clone(),
+        thread: Some(thread),
+    };
+
+    (NonBlocking { lines }, guard)
+}
+
+/// The writer from [`non_blocking`], to give to a tracing subscriber.
+/// Writing only blocks when the queue of lines is full.
+#[derive(Debug, Clone)]
+pub struct NonBlocking {
+    lines: mpsc::SyncSender<Message>,
+}
+
+impl Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lines
+            .send(Message::Line(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Writes out the lines still queued and stops the writer thread when
+/// dropped. Lines logged after that are lost.
+#[derive(Debug)]
+pub struct LogGuard {
+    lines: mpsc::SyncSender<Message>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        // the shutdown queues up behind the lines already sent
+        let _ = self.lines.send(Message::Shutdown);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_rotation_suffix() {
+        assert_eq!(Rotation::Daily.suffix(0), "1970-01-01");
+        assert_eq!(Rotation::Hourly.suffix(0), "1970-01-01-00");
+
+        // 2023-11-14 22:13:20
+        assert_eq!(Rotation::Daily.suffix(1_700_000_000), "2023-11-14");
+        assert_eq!(Rotation::Hourly.suffix(1_700_000_000), "2023-11-14-22");
+
+        // the leap day of 2000, and the second before it
+        assert_eq!(Rotation::Daily.suffix(951_782_400), "2000-02-29");
+        assert_eq!(Rotation::Hourly.suffix(951_782_399), "2000-02-28-23");
+    }
+
+    #[test]
+    fn test_rolling_file() {
+        let dir = std::env::temp_dir().join(format!("prime_time-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prime_time.log");
+
+        let mut file = RollingFile::new(&path, Rotation::Hourly).unwrap();
+        for (secs, line) in [
+            (1_700_000_000, "one\n"),
+            (1_700_000_399, "two\n"),
+            (1_700_003_600, "three\n"),
+        ] {
+            file.file_at(secs)
+                .unwrap()
+                .write_all(line.as_bytes())
+                .unwrap();
+        }
+
+        let read = |suffix: &str| {
+            std::fs::read_to_string(dir.join(format!("prime_time.log.{suffix}"))).unwrap()
+        };
+        assert_eq!(read("2023-11-14-22"), "one\ntwo\n");
+        assert_eq!(read("2023-11-14-23"), "three\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rolling_file_unwritable() {
+        let path = std::env::temp_dir().join("prime_time-missing-dir/prime_time.log");
+
+        assert!(RollingFile::new(path, Rotation::Daily).is_err());
+    }
+
+    // a writer collecting what was written
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_guard_writes_out_queued_lines() {
+        let buffer = Buffer::default();
+        let (writer, guard) = non_blocking(buffer.clone());
+
+        for i in 0..1000 {
+            writeln!(writer.make_writer(), "line {i}").unwrap();
+        }
+        drop(guard);
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(written.lines().count(), 1000);
+        assert_eq!(written.lines().last(), Some("line 999"));
+
+        // the writer thread is gone
+        assert!(writeln!(writer.make_writer(), "late").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt;
+
+use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span::Record,
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    registry::LookupSpan,
+};
+
+/// Formats events as one JSON object per line, for log aggregators.
+///
+/// The fields of the spans an event happened in, like the client address of
+/// a connection, are kept as structured fields under `spans`. Use it together
+/// with [`JsonFields`]:
+///
+/// ```
+/// use prime_time::logging::{JsonFields, JsonFormat};
+///
+/// let subscriber = tracing_subscriber::fmt()
+///     .fmt_fields(JsonFields)
+///     .event_format(JsonFormat)
+///     .finish();
+/// # drop(subscriber);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+/// Records span fields as a JSON object, so [`JsonFormat`] can include them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFields;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+
+        // outermost span first
+        let spans: Vec<Value> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut object: Map<String, Value> = span
+                    .extensions()
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                    .unwrap_or_default();
+
+                object.insert("name".to_string(), span.name().into());
+                Value::Object(object)
+            })
+            .collect();
+
+        let metadata = event.metadata();
+        let line = json!({
+            "timestamp": timestamp,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+
+        writeln!(writer, "{line}")
+    }
+}
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    // merge fields recorded after the span was created into its object
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+// Collects fields into a JSON object, keeping numbers and booleans as such
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Captured;
+
+    #[test]
+    fn test_json_format() {
+        let captured = Captured::default();
+
+        tracing::subscriber::with_default(captured.json_subscriber(), || {
+            let span = tracing::info_span!("Connection", client = %"127.0.0.1:5000");
+            let _guard = span.enter();
+
+            span.record("client", "127.0.0.1:5001");
+            tracing::info!(number = 7, prime = true, "answered");
+        });
+
+        let line: Value = serde_json::from_str(captured.output().trim_end()).unwrap();
+
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "answered");
+        assert_eq!(line["fields"]["number"], 7);
+        assert_eq!(line["fields"]["prime"], true);
+        assert_eq!(
+            line["spans"],
+            json!([{ "name": "Connection", "client": "127.0.0.1:5001" }])
+        );
+    }
+}
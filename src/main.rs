@@ -1,36 +1,313 @@
-use color_eyre::eyre::Result;
-use std::net::{IpAddr, SocketAddr};
+use color_eyre::eyre::{Result, WrapErr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    fmt::MakeWriter,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
 
+// Every setting can also be given as a PRIME_TIME_* environment variable.
+// Flags win over the environment, which wins over the config file.
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
-    /// IP address to bind to
-    #[arg(default_value = "127.0.0.1")]
-    ip: IpAddr,
+    /// IP address to bind to [default: 127.0.0.1]
+    #[arg(env = "PRIME_TIME_IP")]
+    ip: Option<IpAddr>,
 
-    /// Port to bind to
-    #[arg(default_value = "8080")]
-    port: u16,
+    /// Port to bind to [default: 8080]
+    #[arg(env = "PRIME_TIME_PORT")]
+    port: Option<u16>,
+
+    /// TOML file to load settings from, see config.example.toml. Flags and
+    /// environment variables override the file.
+    #[arg(long, env = "PRIME_TIME_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Connections handled at once [default: 1024]
+    #[arg(long, env = "PRIME_TIME_MAX_CONNECTIONS")]
+    max_connections: Option<NonZeroUsize>,
+
+    /// Seconds a connection may sit idle before it is closed [default: 30]
+    #[arg(long, env = "PRIME_TIME_IDLE_TIMEOUT", value_parser = parse_secs)]
+    idle_timeout: Option<Duration>,
+
+    /// Seconds a single request may take to answer [default: no limit]
+    #[arg(long, env = "PRIME_TIME_REQUEST_TIMEOUT", value_parser = parse_secs)]
+    request_timeout: Option<Duration>,
+
+    /// Serve over UDP, one request per datagram, instead of TCP
+    #[arg(long)]
+    udp: bool,
+
+    /// Serve HTTP/1.1 instead of the line protocol, answering requests
+    /// POSTed to /isPrime
+    #[cfg(feature = "http")]
+    #[cfg_attr(unix, arg(long, conflicts_with_all = ["udp", "unix"]))]
+    #[cfg_attr(not(unix), arg(long, conflicts_with = "udp"))]
+    http: bool,
+
+    /// Serve on a Unix domain socket at this path instead of TCP
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "udp")]
+    unix: Option<PathBuf>,
+
+    /// Threads running connections [default: available parallelism]. 1 runs
+    /// everything on the main thread. Requests are answered on a separate
+    /// pool of `compute_workers` threads either way, so this doesn't limit
+    /// how many numbers are checked at once.
+    #[arg(long, env = "PRIME_TIME_WORKER_THREADS")]
+    worker_threads: Option<NonZeroUsize>,
+
+    /// Primality test for numbers the sieve can't answer [default:
+    /// miller-rabin]
+    #[arg(long, env = "PRIME_TIME_PRIMALITY_ALGORITHM", value_enum)]
+    primality_algorithm: Option<PrimalityAlgorithm>,
+
+    /// Format to write logs in
+    #[arg(long, env = "PRIME_TIME_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Most verbose level to log at. RUST_LOG overrides it when set, either
+    /// as a level or as `target=level` pairs like `info,prime_time=debug`.
+    #[arg(long, env = "PRIME_TIME_LOG_LEVEL", value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Also write logs to this file, with the date appended to its name as
+    /// in `prime_time.log.2024-05-01`. The file gets the same --log-format
+    /// as stdout, without colors, so with json both are JSON lines.
+    #[arg(long, env = "PRIME_TIME_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// How often to start a new log file
+    #[arg(long, env = "PRIME_TIME_LOG_ROTATION", value_enum, default_value_t = LogRotation::Daily, requires = "log_file")]
+    log_rotation: LogRotation,
+
+    /// Address to serve Prometheus metrics on
+    #[cfg(feature = "metrics")]
+    #[arg(long, env = "PRIME_TIME_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogRotation {
+    /// A new file every hour
+    Hourly,
+    /// A new file every day
+    Daily,
+}
+
+impl From<LogRotation> for prime_time::log_file::Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => Self::Hourly,
+            LogRotation::Daily => Self::Daily,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PrimalityAlgorithm {
+    /// Trial division, exact but only practical for small numbers
+    Trial,
+    /// Miller-Rabin, deterministic below 2^64
+    MillerRabin,
+    /// Baillie-PSW, no known false positives
+    Bpsw,
+}
+
+impl From<PrimalityAlgorithm> for prime_time::PrimalityAlgorithm {
+    fn from(algorithm: PrimalityAlgorithm) -> Self {
+        match algorithm {
+            PrimalityAlgorithm::Trial => Self::Trial,
+            PrimalityAlgorithm::MillerRabin => Self::MillerRabin,
+            PrimalityAlgorithm::Bpsw => Self::Bpsw,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => LevelFilter::TRACE,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Error => LevelFilter::ERROR,
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+// The log filter from RUST_LOG if it's set, otherwise from --log-level
+fn log_filter(level: LogLevel) -> Result<Targets> {
+    match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.trim().is_empty() => directives
+            .parse()
+            .wrap_err_with(|| format!("Invalid RUST_LOG `{directives}`")),
+        _ => Ok(Targets::new().with_default(level)),
+    }
+}
+
+// A layer writing logs in `format` to `writer`. Colors are left to
+// tracing_subscriber to decide unless turned off.
+fn fmt_layer<W>(
+    format: LogFormat,
+    writer: W,
+    colors: bool,
+) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    let layer = if colors {
+        layer
+    } else {
+        layer.with_ansi(false)
+    };
+
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(prime_time::logging::JsonFields)
+            .event_format(prime_time::logging::JsonFormat)
+            .boxed(),
+    }
+}
+
+// Parse a duration given as a number of seconds, like the config file
+fn parse_secs(secs: &str) -> Result<Duration, String> {
+    let secs = secs.parse::<f64>().map_err(|e| e.to_string())?;
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+fn main() -> Result<()> {
     // Setup error handling with color output
     color_eyre::install()?;
 
-    // Setup a tracing subscriber that prints logs to stdout
-    tracing_subscriber::fmt::init();
-
     // get CLI args
     let cli = Cli::parse();
 
-    // create socket address
-    let socket = SocketAddr::new(cli.ip, cli.port);
+    // build the runtime by hand so the number of worker threads can be chosen
+    let mut runtime = match cli.worker_threads.map(NonZeroUsize::get) {
+        Some(1) => tokio::runtime::Builder::new_current_thread(),
+        Some(threads) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads);
+            builder
+        }
+        None => tokio::runtime::Builder::new_multi_thread(),
+    };
+
+    runtime.enable_all().build()?.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    // Setup a tracing subscriber that prints logs to stdout, and to a file
+    // if asked. The guard is held until the server has stopped so the last
+    // lines reach the file.
+    let filter = log_filter(cli.log_level)?;
+    let mut layers = vec![fmt_layer(cli.log_format, std::io::stdout, true)];
+    let _log_guard = match &cli.log_file {
+        Some(path) => {
+            let file = prime_time::log_file::RollingFile::new(path, cli.log_rotation.into())
+                .wrap_err_with(|| format!("Failed to open log file {}", path.display()))?;
+            let (writer, guard) = prime_time::log_file::non_blocking(file);
+
+            layers.push(fmt_layer(cli.log_format, writer, false));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .init();
+
+    let config = match &cli.config {
+        Some(path) => {
+            let toml = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("Failed to read config file {}", path.display()))?;
+
+            prime_time::ServerConfig::from_toml(&toml)
+                .wrap_err_with(|| format!("Failed to load config file {}", path.display()))?
+        }
+        None => prime_time::ServerConfig::default(),
+    };
+
+    // create socket address, falling back to the config for anything not given
+    let socket = SocketAddr::new(
+        cli.ip.unwrap_or(config.addr.ip()),
+        cli.port.unwrap_or(config.addr.port()),
+    );
+
+    let mut config = prime_time::ServerConfigBuilder::from(config).addr(socket);
+
+    if let Some(max_connections) = cli.max_connections {
+        config = config.max_connections(max_connections.get());
+    }
+    if let Some(idle_timeout) = cli.idle_timeout {
+        config = config.idle_timeout(idle_timeout);
+    }
+    if let Some(request_timeout) = cli.request_timeout {
+        config = config.request_timeout(request_timeout);
+    }
+    if let Some(algorithm) = cli.primality_algorithm {
+        config = config.primality_algorithm(algorithm.into());
+    }
+
+    #[cfg(feature = "metrics")]
+    let config = match cli.metrics_addr {
+        Some(addr) => config.metrics_addr(addr),
+        None => config,
+    };
+
+    let config = config.build()?;
 
     // run the server
-    prime_time::run(socket).await?;
+    #[cfg(unix)]
+    if let Some(path) = &cli.unix {
+        prime_time::run_unix_with_config(path, config).await?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "http")]
+    if cli.http {
+        prime_time::run_http_with_config(config).await?;
+        return Ok(());
+    }
+
+    if cli.udp {
+        prime_time::run_udp_with_config(config).await?;
+    } else {
+        prime_time::run_with_config(config).await?;
+    }
 
     Ok(())
 }
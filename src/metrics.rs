@@ -0,0 +1,282 @@
+use std::{
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::PrimeTimeError;
+
+/// The reasons a request can be malformed for, as reported by
+/// [`Metrics::malformed_by_reason`]
+pub const MALFORMED_REASONS: [&str; 3] = ["invalid_json", "line_too_long", "invalid_request"];
+
+/// Server-wide counters, updated as connections and requests are handled
+#[derive(Debug)]
+pub struct Metrics {
+    requests: AtomicU64,
+    primes: AtomicU64,
+    composites: AtomicU64,
+    // indexed like MALFORMED_REASONS
+    malformed: [AtomicU64; 3],
+    active_connections: AtomicI64,
+    started: Instant,
+    // the same moment by the wall clock, for reporting
+    started_at: SystemTime,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: AtomicU64::default(),
+            primes: AtomicU64::default(),
+            composites: AtomicU64::default(),
+            malformed: Default::default(),
+            active_connections: AtomicI64::default(),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Record a successfully answered request
+    pub fn record_request(&self, prime: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+
+        if prime {
+            self.primes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.composites.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a request that was rejected as malformed because of `error`
+    pub fn record_malformed(&self, error: &PrimeTimeError) {
+        let reason = match error {
+            PrimeTimeError::DeserializeError(_) => 0,
+            PrimeTimeError::LineTooLong(_) => 1,
+            _ => 2,
+        };
+
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.malformed[reason].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a connection being opened
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a connection being closed
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Total requests received, including malformed ones
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with `prime: true`
+    pub fn primes(&self) -> u64 {
+        self.primes.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with `prime: false`
+    pub fn composites(&self) -> u64 {
+        self.composites.load(Ordering::Relaxed)
+    }
+
+    /// Requests rejected as malformed
+    pub fn malformed(&self) -> u64 {
+        self.malformed_by_reason().map(|(_, count)| count).sum()
+    }
+
+    /// Requests rejected as malformed, counted separately for each of
+    /// [`MALFORMED_REASONS`]: lines that aren't JSON, lines over the length
+    /// limit and JSON that isn't a valid request
+    pub fn malformed_by_reason(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        MALFORMED_REASONS
+            .into_iter()
+            .zip(&self.malformed)
+            .map(|(reason, count)| (reason, count.load(Ordering::Relaxed)))
+    }
+
+    /// Connections currently open
+    pub fn active_connections(&self) -> i64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Time since the counters were created, which is when the server started
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// When the counters were created by the wall clock
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            ("requests", "Requests received", self.requests()),
+            ("primes", "Requests answered as prime", self.primes()),
+            (
+                "composites",
+                "Requests answered as not prime",
+                self.composites(),
+            ),
+            ("malformed", "Malformed requests", self.malformed()),
+        ];
+
+        for (name, help, value) in counters {
+            out.push_str(&format!(
+                "# HELP prime_time_{name}_total {help}\n\
+                 # TYPE prime_time_{name}_total counter\n\
+                 prime_time_{name}_total {value}\n"
+            ));
+        }
+
+        out.push_str(
+            "# HELP prime_time_malformed_by_reason_total Malformed requests by reason\n\
+             # TYPE prime_time_malformed_by_reason_total counter\n",
+        );
+        for (reason, count) in self.malformed_by_reason() {
+            out.push_str(&format!(
+                "prime_time_malformed_by_reason_total{{reason=\"{reason}\"}} {count}\n"
+            ));
+        }
+
+        out.push_str(&format!(
+            "# HELP prime_time_active_connections Connections currently open\n\
+             # TYPE prime_time_active_connections gauge\n\
+             prime_time_active_connections {}\n",
+            self.active_connections()
+        ));
+
+        let started_at = self
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "# HELP prime_time_start_time_seconds Start time of the server since the Unix epoch\n\
+             # TYPE prime_time_start_time_seconds gauge\n\
+             prime_time_start_time_seconds {:.3}\n\
+             # HELP prime_time_uptime_seconds Time since the server started\n\
+             # TYPE prime_time_uptime_seconds gauge\n\
+             prime_time_uptime_seconds {:.3}\n",
+            started_at.as_secs_f64(),
+            self.uptime().as_secs_f64()
+        ));
+
+        out
+    }
+}
+
+// Serve the metrics over a minimal HTTP/1.1 endpoint. Every request gets the
+// metrics regardless of its path.
+#[cfg(feature = "metrics")]
+pub(crate) async fn serve_metrics(
+    listener: tokio::net::TcpListener,
+    metrics: std::sync::Arc<Metrics>,
+) -> Result<(), crate::PrimeTimeError> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.split();
+            let mut reader = BufReader::new(reader);
+
+            // skip the request head, it ends with an empty line
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match reader.read_line(&mut line).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) if line.trim_end().is_empty() => break,
+                    Ok(_) => (),
+                }
+            }
+
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\
+                 \r\n\
+                 {body}",
+                body.len()
+            );
+
+            if let Err(e) = writer.write_all(response.as_bytes()).await {
+                tracing::warn!("Failed to write metrics: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+
+        metrics.connection_opened();
+        metrics.record_request(true);
+        metrics.record_request(false);
+        metrics.record_malformed(&PrimeTimeError::LineTooLong(16));
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("prime_time_requests_total 3\n"));
+        assert!(rendered.contains("prime_time_primes_total 1\n"));
+        assert!(rendered.contains("prime_time_composites_total 1\n"));
+        assert!(rendered.contains("prime_time_malformed_total 1\n"));
+        assert!(
+            rendered.contains("prime_time_malformed_by_reason_total{reason=\"line_too_long\"} 1\n")
+        );
+        assert!(
+            rendered.contains("prime_time_malformed_by_reason_total{reason=\"invalid_json\"} 0\n")
+        );
+        assert!(rendered.contains("prime_time_active_connections 1\n"));
+        assert!(rendered.contains("\nprime_time_start_time_seconds "));
+        assert!(rendered.contains("\nprime_time_uptime_seconds 0."));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        use std::sync::Arc;
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_request(true);
+        tokio::spawn(serve_metrics(listener, metrics));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("prime_time_primes_total 1\n"));
+    }
+}
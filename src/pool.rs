@@ -0,0 +1,182 @@
+use std::{
+    cell::RefCell,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::PrimeTimeError;
+
+// A unit of work for the pool, sending its result back when done
+type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    // The flag the job running on this worker is cancelled through
+    static CANCELLED: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+// Whether the caller has stopped waiting for the job running on this thread,
+// for long loops to check so they can give up early. Always false off the
+// pool.
+pub(crate) fn cancelled() -> bool {
+    CANCELLED.with_borrow(|cancelled| {
+        cancelled
+            .as_ref()
+            .is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+    })
+}
+
+// Cancels a job when the caller's future is dropped, like when it times out
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// A fixed number of threads doing CPU-bound work for connections. Work waits
+// in a bounded queue, so when every worker is busy and the queue is full
+// callers wait to submit instead of starting yet more threads.
+#[derive(Debug)]
+pub(crate) struct ComputePool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ComputePool {
+    // Start `workers` threads, with room for as many jobs again to queue up
+    // behind them. The threads exit once the pool is dropped and the queue
+    // is drained. Panics if `workers` is 0, which config validation rejects.
+    pub(crate) fn new(workers: usize) -> Self {
+        assert!(workers > 0, "a compute pool needs at least one worker");
+
+        let (jobs, queue) = mpsc::channel::<Job>(workers);
+        let queue = Arc::new(Mutex::new(queue));
+
+        for i in 0..workers {
+            let queue = queue.clone();
+
+            std::thread::Builder::new()
+                .name(format!("prime_time-compute-{i}"))
+                .spawn(move || loop {
+                    // only one idle worker waits on the queue at a time, the
+                    // rest wait for the lock
+                    let job = queue
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .blocking_recv();
+
+                    match job {
+                        Some(job) => job(),
+                        None => return,
+                    }
+                })
+                .expect("failed to start compute worker");
+        }
+
+        Self { jobs }
+    }
+
+    // Run `work` on a worker, waiting for a place in the queue first.
+    // Dropping the returned future cancels the job: it is skipped if it
+    // hasn't started, and `cancelled` turns true for it if it has.
+    pub(crate) async fn run<T, F>(&self, work: F) -> Result<T, PrimeTimeError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel = CancelOnDrop(cancelled.clone());
+
+        // a panic is caught so the worker lives on, the caller sees the
+        // result dropped
+        let job: Job = Box::new(move || {
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+
+            CANCELLED.set(Some(cancelled));
+            let value = std::panic::catch_unwind(AssertUnwindSafe(work));
+            CANCELLED.set(None);
+
+            if let Ok(value) = value {
+                let _ = done.send(value);
+            }
+        });
+
+        self.jobs
+            .send(job)
+            .await
+            .map_err(|_| PrimeTimeError::WorkerFailed)?;
+        result.await.map_err(|_| PrimeTimeError::WorkerFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_concurrency_is_bounded() {
+        let pool = Arc::new(ComputePool::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (pool, running, most) = (pool.clone(), running.clone(), most.clone());
+
+                tokio::spawn(async move {
+                    pool.run(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(50));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_panic_leaves_worker_running() {
+        let pool = ComputePool::new(1);
+
+        let result = pool.run(|| panic!("checker failed")).await;
+        assert!(matches!(result, Err(PrimeTimeError::WorkerFailed)));
+
+        assert_eq!(pool.run(|| 7).await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_job_is_cancelled() {
+        let pool = ComputePool::new(1);
+        assert!(!cancelled());
+
+        // the job spins until it notices the caller has given up on it
+        let spinning = pool.run(|| while !cancelled() {});
+        assert!(tokio::time::timeout(Duration::from_millis(50), spinning)
+            .await
+            .is_err());
+
+        // which frees the only worker for the next job
+        let next = tokio::time::timeout(Duration::from_secs(5), pool.run(cancelled));
+        assert!(!next.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, OnceLock},
+};
+
+use num_bigint::{BigInt, BigUint};
+use num_prime::{nt_funcs::is_prime, PrimalityTestConfig};
+use serde::Deserialize;
+
+use crate::Sieve;
+
+// Trial division tries the primes below this before Miller-Rabin, all 303
+// of them
+const SMALL_PRIMES_BELOW: u32 = 2000;
+
+/// Decides whether numbers are prime, so the test behind the server can be
+/// swapped out. The sieve and cache still sit in front of it.
+pub trait PrimalityChecker: Debug + Send + Sync {
+    /// Whether `n` is prime. Negative numbers are never prime.
+    fn is_prime(&self, n: &BigInt) -> bool;
+}
+
+/// The default checker, using `num_prime`'s test.
+///
+/// Numbers below 2^64 are checked deterministically. Larger ones go through
+/// rounds of Miller-Rabin, each of which lets a composite through with
+/// probability at most 1/4, so a composite is called prime with probability
+/// at most 4^-rounds.
+///
+/// By default the rounds grow with the size of the number, one for every 32
+/// bits. That targets an error probability of at most 2^-(bits/16), never
+/// worse than 2^-10 and reaching 2^-32 at 512 bits, where the rounds stop
+/// growing. The 1/4 bound is far from tight for random bases on large
+/// numbers, and each round of a 1000 digit prime costs a modular
+/// exponentiation taking around 20ms, so more rounds would mostly buy time
+/// spent. [`NumPrimeChecker::with_rounds`] runs the same number of rounds on
+/// every number instead.
+///
+/// Before any of that the number is divided by the primes below 2000, which
+/// turns away most composites for the price of a few hundred small
+/// divisions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumPrimeChecker {
+    rounds: Option<usize>,
+    trial_division: bool,
+}
+
+impl NumPrimeChecker {
+    /// The fewest rounds the default checker runs, two with fixed bases and
+    /// three with random ones
+    pub const MIN_ROUNDS: usize = 5;
+
+    /// The most rounds the default checker runs, on numbers of 512 bits
+    /// and above
+    pub const MAX_ROUNDS: usize = 16;
+
+    /// A checker running `rounds` rounds of Miller-Rabin on large numbers
+    /// whatever their size. The first two use the bases 2 and 3, the rest
+    /// use random bases so crafted pseudoprimes can't reliably get through.
+    /// At least one round is always run, so 0 counts as 1.
+    pub fn with_rounds(rounds: usize) -> Self {
+        Self {
+            rounds: Some(rounds.max(1)),
+            ..Self::default()
+        }
+    }
+
+    /// Whether to try dividing by the small primes first, on by default.
+    /// Only worth turning off to measure what it saves.
+    pub fn trial_division(mut self, enabled: bool) -> Self {
+        self.trial_division = enabled;
+        self
+    }
+
+    /// The fixed rounds of Miller-Rabin run on numbers of 2^64 and above, or
+    /// `None` if they scale with the number
+    pub fn rounds(&self) -> Option<usize> {
+        self.rounds
+    }
+
+    /// Rounds of Miller-Rabin run on `n`
+    pub fn rounds_for(&self, n: &BigInt) -> usize {
+        self.rounds.unwrap_or_else(|| {
+            usize::try_from(n.bits().div_ceil(32))
+                .unwrap_or(usize::MAX)
+                .clamp(Self::MIN_ROUNDS, Self::MAX_ROUNDS)
+        })
+    }
+}
+
+impl Default for NumPrimeChecker {
+    fn default() -> Self {
+        Self {
+            rounds: None,
+            trial_division: true,
+        }
+    }
+}
+
+impl PrimalityChecker for NumPrimeChecker {
+    fn is_prime(&self, n: &BigInt) -> bool {
+        if n.sign() == num_bigint::Sign::Minus {
+            return false;
+        }
+
+        if self.trial_division {
+            if let Some(prime) = check_small_factors(n.magnitude()) {
+                return prime;
+            }
+        }
+
+        let rounds = self.rounds_for(n);
+        let mut config = PrimalityTestConfig::default();
+        config.sprp_trials = rounds.min(2);
+        config.sprp_random_trials = rounds - config.sprp_trials;
+
+        is_prime(n.magnitude(), Some(config)).probably()
+    }
+}
+
+// The primes below SMALL_PRIMES_BELOW, sieved on first use
+fn small_primes() -> &'static [u32] {
+    static PRIMES: OnceLock<Vec<u32>> = OnceLock::new();
+
+    PRIMES.get_or_init(|| {
+        Sieve::new(SMALL_PRIMES_BELOW as usize)
+            .primes()
+            .map(|p| p as u32)
+            .collect()
+    })
+}
+
+// Settle `n` by dividing it by the small primes if that's enough: it is
+// composite if one of them divides it, unless it is that prime, and prime if
+// none do and it is below the square of the largest
+fn check_small_factors(n: &BigUint) -> Option<bool> {
+    for &p in small_primes() {
+        if (n % p).bits() == 0 {
+            return Some(*n == BigUint::from(p));
+        }
+    }
+
+    let below = u64::from(SMALL_PRIMES_BELOW);
+    (*n < BigUint::from(below * below)).then(|| *n > BigUint::from(1u32))
+}
+
+/// Checks numbers by dividing them by 2 and every odd number up to their
+/// square root.
+///
+/// The answer is always exact and there is no setup, so it can beat the
+/// other checkers on numbers below a few million, but the work grows with
+/// the square root of the number. Anything much past 2^40 takes seconds and
+/// large numbers never finish, so pair it with
+/// [`ServerConfig::request_timeout`](crate::ServerConfig::request_timeout).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrialDivisionChecker;
+
+impl PrimalityChecker for TrialDivisionChecker {
+    fn is_prime(&self, n: &BigInt) -> bool {
+        if n.sign() == num_bigint::Sign::Minus {
+            return false;
+        }
+
+        match u64::try_from(n) {
+            Ok(n) => is_prime_by_division(n),
+            Err(_) => is_big_prime_by_division(n.magnitude()),
+        }
+    }
+}
+
+// Trial division in machine words, for numbers that fit
+fn is_prime_by_division(n: u64) -> bool {
+    if n < 4 {
+        return n >= 2;
+    }
+    if n.is_multiple_of(2) {
+        return false;
+    }
+
+    let n = u128::from(n);
+    let mut divisor = 3u128;
+    while divisor * divisor <= n {
+        if n.is_multiple_of(divisor) {
+            return false;
+        }
+        divisor += 2;
+    }
+
+    true
+}
+
+// Trial division for numbers of 2^64 and above, which are never below 4
+fn is_big_prime_by_division(n: &BigUint) -> bool {
+    if !n.bit(0) {
+        return false;
+    }
+
+    let mut divisor = BigUint::from(3u32);
+    while &divisor * &divisor <= *n {
+        if (n % &divisor).bits() == 0 {
+            return false;
+        }
+        divisor += 2u32;
+    }
+
+    true
+}
+
+/// Checks numbers with the Baillie-PSW test: a strong probable prime test
+/// to base 2 followed by a strong Lucas probable prime test.
+///
+/// No composite is known to pass both, and none exist below 2^64, so unlike
+/// [`NumPrimeChecker`] there are no rounds to tune and no randomness. It
+/// costs about as much as three rounds of Miller-Rabin, which makes it the
+/// better choice for very large numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BpswChecker;
+
+impl PrimalityChecker for BpswChecker {
+    fn is_prime(&self, n: &BigInt) -> bool {
+        if n.sign() == num_bigint::Sign::Minus {
+            return false;
+        }
+
+        is_prime(n.magnitude(), Some(PrimalityTestConfig::bpsw())).probably()
+    }
+}
+
+/// The primality tests that can be chosen by name, in a config file as
+/// `primality_algorithm` or with `--primality-algorithm`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrimalityAlgorithm {
+    /// [`TrialDivisionChecker`], exact and fastest for small numbers but
+    /// hopeless for large ones
+    Trial,
+    /// [`NumPrimeChecker`], with rounds scaling with the number unless
+    /// [`ServerConfig::miller_rabin_rounds`](crate::ServerConfig::miller_rabin_rounds)
+    /// fixes them
+    #[default]
+    MillerRabin,
+    /// [`BpswChecker`], with no known false positives at any size
+    Bpsw,
+}
+
+impl PrimalityAlgorithm {
+    /// A checker running this algorithm. `miller_rabin_rounds` is only used
+    /// by [`PrimalityAlgorithm::MillerRabin`], which scales its rounds with
+    /// the number when it's `None`.
+    pub fn checker(self, miller_rabin_rounds: Option<usize>) -> Arc<dyn PrimalityChecker> {
+        match self {
+            Self::Trial => Arc::new(TrialDivisionChecker),
+            Self::MillerRabin => Arc::new(match miller_rabin_rounds {
+                Some(rounds) => NumPrimeChecker::with_rounds(rounds),
+                None => NumPrimeChecker::default(),
+            }),
+            Self::Bpsw => Arc::new(BpswChecker),
+        }
+    }
+}
+
+/// Check whether a number is prime with the default [`NumPrimeChecker`].
+/// Negative numbers are never prime.
+pub fn is_number_prime(n: &BigInt) -> bool {
+    NumPrimeChecker::default().is_prime(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_number_prime_small() {
+        assert!(!is_number_prime(&BigInt::from(0)));
+        assert!(!is_number_prime(&BigInt::from(1)));
+        assert!(is_number_prime(&BigInt::from(2)));
+        assert!(is_number_prime(&BigInt::from(3)));
+    }
+
+    #[test]
+    fn test_is_number_prime_large() {
+        // 2^127 - 1 is a Mersenne prime
+        let n = (BigInt::from(1) << 127) - 1;
+
+        assert!(is_number_prime(&n));
+    }
+
+    #[test]
+    fn test_is_number_prime_negative() {
+        assert!(!is_number_prime(&BigInt::from(-7)));
+    }
+
+    #[test]
+    fn test_num_prime_checker_rounds() {
+        // 2^127 - 1 is prime and 2^127 + 1 is divisible by 3
+        let prime = (BigInt::from(1) << 127) - 1;
+        let composite = (BigInt::from(1) << 127) + 1;
+
+        for rounds in [1, 2, 5, 20] {
+            let checker = NumPrimeChecker::with_rounds(rounds);
+
+            assert!(checker.is_prime(&prime), "{rounds}");
+            assert!(!checker.is_prime(&composite), "{rounds}");
+            assert!(!checker.is_prime(&-prime.clone()), "{rounds}");
+        }
+    }
+
+    #[test]
+    fn test_zero_rounds_run_one() {
+        let checker = NumPrimeChecker::with_rounds(0);
+        assert_eq!(checker.rounds(), Some(1));
+
+        // the square of 2^64 + 13 has no small factors for trial division
+        // to find, so only Miller-Rabin can tell it is composite
+        let prime: BigInt = (BigInt::from(1) << 64) + 13;
+        assert!(checker.is_prime(&prime));
+        assert!(!checker.is_prime(&(&prime * &prime)));
+    }
+
+    #[test]
+    fn test_rounds_scale_with_size() {
+        let checker = NumPrimeChecker::default();
+        let rounds = |bits: u32| checker.rounds_for(&(BigInt::from(1) << (bits - 1)));
+
+        assert_eq!(rounds(65), NumPrimeChecker::MIN_ROUNDS);
+        assert_eq!(rounds(160), NumPrimeChecker::MIN_ROUNDS);
+        assert_eq!(rounds(256), 8);
+        assert_eq!(rounds(512), NumPrimeChecker::MAX_ROUNDS);
+        assert_eq!(rounds(2048), NumPrimeChecker::MAX_ROUNDS);
+        assert_eq!(rounds(100_000), NumPrimeChecker::MAX_ROUNDS);
+        assert!(rounds(384) > rounds(256));
+
+        // fixed rounds ignore the size
+        let checker = NumPrimeChecker::with_rounds(3);
+        assert_eq!(checker.rounds_for(&BigInt::from(u64::MAX)), 3);
+        assert_eq!(checker.rounds_for(&(BigInt::from(1) << 4096)), 3);
+    }
+
+    #[test]
+    fn test_small_factors_short_circuit() {
+        let check = |n: BigInt| check_small_factors(n.magnitude());
+
+        assert_eq!(small_primes().len(), 303);
+        assert_eq!(check(BigInt::from(0)), Some(false));
+        assert_eq!(check(BigInt::from(1)), Some(false));
+        assert_eq!(check(BigInt::from(2)), Some(true));
+        assert_eq!(check(BigInt::from(1999)), Some(true));
+        assert_eq!(check(BigInt::from(1999 * 1999)), Some(false));
+
+        // no factor below 2000 but too large to be sure
+        assert_eq!(check(BigInt::from(2003 * 2011)), None);
+        assert_eq!(check((BigInt::from(1) << 127) - 1), None);
+
+        // 2^127 + 1 is divisible by 3, and 1997 * (2^89 - 1) by 1997
+        assert_eq!(check((BigInt::from(1) << 127) + 1), Some(false));
+        assert_eq!(
+            check(BigInt::from(1997) * ((BigInt::from(1) << 89) - 1)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_trial_division_agrees() {
+        let with = NumPrimeChecker::default();
+        let without = NumPrimeChecker::default().trial_division(false);
+
+        for n in (0..5000).chain([2003 * 2011, 1999 * 1999]) {
+            let n = BigInt::from(n);
+            assert_eq!(with.is_prime(&n), without.is_prime(&n), "{n}");
+        }
+    }
+
+    #[test]
+    fn test_algorithms_agree() {
+        // Carmichael numbers and strong pseudoprimes to small bases are
+        // included since they fool weaker tests
+        let primes = [2u64, 3, 5, 7, 97, 7919, 1_000_003, 2_147_483_647];
+        let composites = [
+            0u64,
+            1,
+            4,
+            9,
+            91,
+            561,
+            1105,
+            2047,
+            3_215_031_751,
+            1_000_003 * 1_000_033,
+        ];
+
+        for algorithm in [
+            PrimalityAlgorithm::Trial,
+            PrimalityAlgorithm::MillerRabin,
+            PrimalityAlgorithm::Bpsw,
+        ] {
+            let checker = algorithm.checker(None);
+
+            for n in primes {
+                assert!(checker.is_prime(&BigInt::from(n)), "{algorithm:?} {n}");
+                assert!(!checker.is_prime(&-BigInt::from(n)), "{algorithm:?} -{n}");
+            }
+            for n in composites {
+                assert!(!checker.is_prime(&BigInt::from(n)), "{algorithm:?} {n}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_algorithms_agree_above_u64() {
+        // 2^64 + 13 is the smallest prime above 2^64, too large for trial
+        // division to finish quickly. 2^64 + 1 = 274177 * 67280421310721.
+        let prime = (BigInt::from(1) << 64) + 13;
+        let composite = (BigInt::from(1) << 64) + 1;
+
+        assert!(BpswChecker.is_prime(&prime));
+        assert!(is_number_prime(&prime));
+
+        assert!(!TrialDivisionChecker.is_prime(&composite));
+        assert!(!BpswChecker.is_prime(&composite));
+        assert!(!is_number_prime(&composite));
+    }
+}
@@ -0,0 +1,630 @@
+use std::collections::BTreeMap;
+
+use num_bigint::BigInt;
+use num_traits::Zero;
+use serde::{de::Error, Deserialize, Serialize};
+use serde_json::{Number, Value};
+
+// Longest integer a number literal is read as, in decimal digits. An
+// exponent like `1e200000000` would otherwise take minutes to expand.
+const MAX_INTEGER_DIGITS: u64 = 1_000_000;
+
+/// A request received from a client. It serializes to the same JSON a client
+/// would send, so it can be used to build requests too.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Request {
+    #[serde(deserialize_with = "deserialize_method")]
+    pub method: String,
+    #[serde(
+        serialize_with = "serialize_number",
+        deserialize_with = "deserialize_number"
+    )]
+    pub number: RequestNumber,
+}
+
+impl Request {
+    /// A request calling `method` on `number`
+    ///
+    /// ```
+    /// use num_bigint::BigInt;
+    /// use prime_time::{Request, RequestNumber};
+    ///
+    /// let request = Request::new("isPrime", RequestNumber::BigInt(BigInt::from(7)));
+    /// assert_eq!(request.to_json_line(), "{\"method\":\"isPrime\",\"number\":7}\n");
+    /// ```
+    pub fn new(method: impl Into<String>, number: RequestNumber) -> Self {
+        Self {
+            method: method.into(),
+            number,
+        }
+    }
+
+    /// The request as a line of JSON ready to send, with its trailing
+    /// newline. Integers are written exactly however large they are and
+    /// floats keep their decimal point, so the server reads back the same
+    /// [`RequestNumber`]. JSON has no infinities or NaN, those are written
+    /// as `null` and the server rejects them as malformed.
+    pub fn to_json_line(&self) -> String {
+        // strings and numbers always serialize
+        let mut line = serde_json::to_string(self).expect("request serializes");
+        line.push('\n');
+        line
+    }
+}
+
+// A request that may only have the fields of a Request, for
+// ServerConfig::strict_fields
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StrictRequest {
+    #[serde(deserialize_with = "deserialize_method")]
+    method: String,
+    #[serde(deserialize_with = "deserialize_number")]
+    number: RequestNumber,
+}
+
+impl From<StrictRequest> for Request {
+    fn from(request: StrictRequest) -> Self {
+        Self {
+            method: request.method,
+            number: request.number,
+        }
+    }
+}
+
+/// The "number" field of a request.
+///
+/// Which variant a number gets depends on how it is written, not only on its
+/// value:
+///
+/// - a literal without a decimal point or exponent is an integer, like `42`
+///   or `-0`
+/// - a literal with an exponent is an integer if its value is whole, like
+///   `1e2` or `2.5e1`, and a float otherwise
+/// - any other literal with a decimal point is a float, even if the
+///   fraction is zero, like `42.0`
+///
+/// Floats are never prime unless
+/// [`ServerConfig::treat_integral_floats_as_int`](crate::ServerConfig::treat_integral_floats_as_int)
+/// is set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestNumber {
+    BigInt(BigInt),
+    Float(f64),
+}
+
+// Implement a custom deserializer for the "number" field
+fn deserialize_number<'de, D>(deserializer: D) -> Result<RequestNumber, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // anything but a number gets named in the error, serde's own message
+    // only lists what it expected
+    let num = match Value::deserialize(deserializer)? {
+        Value::Number(num) => num,
+        other => {
+            return Err(D::Error::custom(format!(
+                "number must be a JSON number, not {}",
+                json_type(&other)
+            )))
+        }
+    };
+
+    let literal = num.to_string();
+    if integer_digits(&literal) > MAX_INTEGER_DIGITS {
+        return Err(D::Error::custom(format!(
+            "number has more than {MAX_INTEGER_DIGITS} digits"
+        )));
+    }
+
+    // classify by the literal as written, see RequestNumber. Checking as_f64
+    // first would turn every integer into a float.
+    if let Some(n) = parse_integer(&literal) {
+        return Ok(RequestNumber::BigInt(n));
+    }
+
+    // try to parse the number as a f64
+    if let Some(f) = num.as_f64() {
+        return Ok(RequestNumber::Float(f));
+    }
+
+    // If we get here, the number is invalid
+    Err(D::Error::custom("Invalid number value"))
+}
+
+// The "method" field, naming what was sent instead if it isn't a string
+fn deserialize_method<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(method) => Ok(method),
+        other => Err(D::Error::custom(format!(
+            "method must be a string, not {}",
+            json_type(&other)
+        ))),
+    }
+}
+
+// The kind of JSON value, for error messages
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+// Serialize the "number" field as a JSON number, keeping integers exact.
+// Whole floats are written out in full with a decimal point, since `1e300`
+// would be read back as an integer.
+fn serialize_number<S>(number: &RequestNumber, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match number {
+        RequestNumber::BigInt(n) => serialize_bigint(n, serializer),
+        RequestNumber::Float(f) if f.is_finite() && f.fract() == 0.0 => {
+            let n: Number = format!("{f}.0")
+                .parse()
+                .map_err(serde::ser::Error::custom)?;
+            n.serialize(serializer)
+        }
+        RequestNumber::Float(f) => serializer.serialize_f64(*f),
+    }
+}
+
+fn serialize_opt_number<S>(number: &Option<RequestNumber>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match number {
+        Some(number) => serialize_number(number, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_opt_number<'de, D>(deserializer: D) -> Result<Option<RequestNumber>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<Value>::deserialize(deserializer)?
+        .map(|n| deserialize_number(n).map_err(D::Error::custom))
+        .transpose()
+}
+
+// Parse a JSON number literal as an integer. Literals with an exponent count
+// if their value is a whole number, so `2e3` and `2.5e1` are integers but
+// `2.5e0` isn't. Without an exponent a decimal point makes it a float, like
+// `7.0`. None for exponents that would make it longer than
+// MAX_INTEGER_DIGITS.
+fn parse_integer(literal: &str) -> Option<BigInt> {
+    let Some((mantissa, exponent)) = literal.split_once(['e', 'E']) else {
+        return BigInt::parse_bytes(literal.as_bytes(), 10);
+    };
+
+    let exponent: i64 = exponent.parse().ok()?;
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    // the value is digits * 10^shift
+    let digits = format!("{whole}{fraction}");
+    let shift = exponent - fraction.len() as i64;
+
+    if shift >= 0 {
+        let n = BigInt::parse_bytes(digits.as_bytes(), 10)?;
+        if n.is_zero() {
+            return Some(n);
+        }
+
+        let shift = u32::try_from(shift)
+            .ok()
+            .filter(|&shift| u64::from(shift) <= MAX_INTEGER_DIGITS)?;
+        return Some(n * BigInt::from(10).pow(shift));
+    }
+
+    // a negative shift only leaves an integer if it drops trailing zeros
+    let unsigned = digits.trim_start_matches('-');
+    let drop = usize::try_from(shift.unsigned_abs()).ok()?;
+    let kept = unsigned.len().saturating_sub(drop);
+
+    if !unsigned[kept..].bytes().all(|d| d == b'0') {
+        return None;
+    }
+
+    match kept {
+        0 => Some(BigInt::from(0)),
+        _ => BigInt::parse_bytes(&digits.as_bytes()[..digits.len() - drop], 10),
+    }
+}
+
+// The number of digits in the whole part of a JSON number literal's value,
+// without building the number. `1e3` has 4, `12.5` has 2.
+pub(crate) fn integer_digits(literal: &str) -> u64 {
+    let literal = literal.trim_start_matches('-');
+    let (mantissa, exponent) = match literal.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => match exponent.parse::<i64>() {
+            Ok(exponent) => (mantissa, exponent),
+            // too large to even parse
+            Err(_) if !exponent.starts_with('-') => return u64::MAX,
+            Err(_) => return 0,
+        },
+        None => (literal, 0),
+    };
+
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{whole}{fraction}");
+
+    // the decimal point sits after the whole part, shifted by the exponent,
+    // and leading zeros don't count. Zero has none however it is written.
+    let leading_zeros = digits.len() - digits.trim_start_matches('0').len();
+    if leading_zeros == digits.len() {
+        return 0;
+    }
+
+    let places = (whole.len() as i64 - leading_zeros as i64).saturating_add(exponent);
+
+    places.max(0) as u64
+}
+
+/// A response sent back to a client
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Response {
+    pub method: String,
+    pub prime: bool,
+    /// The smallest prime factor of a composite number, only sent when
+    /// [`ServerConfig::composite_factor`](crate::ServerConfig::composite_factor)
+    /// is set
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_opt_bigint",
+        deserialize_with = "deserialize_opt_bigint"
+    )]
+    pub factor: Option<BigInt>,
+    /// The number that was asked about, exactly as it was read, only sent
+    /// when [`ServerConfig::echo_number`](crate::ServerConfig::echo_number)
+    /// is set
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_opt_number",
+        deserialize_with = "deserialize_opt_number"
+    )]
+    pub number: Option<RequestNumber>,
+    /// The bit length of the number, only sent for integers when
+    /// [`ServerConfig::number_bits`](crate::ServerConfig::number_bits) is
+    /// set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u64>,
+    /// The decimal digits in the number, without its sign, only sent for
+    /// integers when
+    /// [`ServerConfig::number_digits`](crate::ServerConfig::number_digits)
+    /// is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digits: Option<u64>,
+}
+
+/// The response to a request that couldn't be answered, sent instead of the
+/// bare malformed response when
+/// [`ServerConfig::structured_errors`](crate::ServerConfig::structured_errors)
+/// is set
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorResponse {
+    /// What kind of error it was, like `invalid_request` or `timeout`
+    pub error: String,
+    /// A human readable description of the error
+    pub detail: String,
+}
+
+/// The response to a `ping` request, used for liveness checks
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PingResponse {
+    pub method: String,
+    pub ok: bool,
+}
+
+/// The response to a `stats` request, with counters for the whole server
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StatsResponse {
+    pub method: String,
+    /// Requests received, including malformed ones
+    pub requests: u64,
+    /// Requests answered with `prime: true`
+    pub primes: u64,
+    /// Requests answered with `prime: false`
+    pub composites: u64,
+    /// Requests rejected as malformed
+    pub malformed: u64,
+    /// Malformed requests for each of
+    /// [`MALFORMED_REASONS`](crate::MALFORMED_REASONS)
+    pub malformed_reasons: BTreeMap<String, u64>,
+    /// Whole seconds since the server started
+    pub uptime_seconds: u64,
+    /// When the server started, in whole seconds since the Unix epoch
+    pub started_at: u64,
+}
+
+/// The response to a `factorize` request
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FactorizeResponse {
+    pub method: String,
+    /// The prime factors in ascending order, repeated by multiplicity
+    #[serde(serialize_with = "serialize_bigints")]
+    pub factors: Vec<BigInt>,
+    /// The bit length of the number, only sent when
+    /// [`ServerConfig::number_bits`](crate::ServerConfig::number_bits) is
+    /// set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u64>,
+    /// The decimal digits in the number, only sent when
+    /// [`ServerConfig::number_digits`](crate::ServerConfig::number_digits)
+    /// is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digits: Option<u64>,
+}
+
+/// The response to a `nextPrime` request
+#[derive(Serialize, Debug, PartialEq)]
+pub struct NextPrimeResponse {
+    pub method: String,
+    /// The smallest prime strictly greater than the requested number
+    #[serde(serialize_with = "serialize_bigint")]
+    pub next: BigInt,
+}
+
+/// The response to a `countPrimesBelow` request
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CountPrimesBelowResponse {
+    pub method: String,
+    /// The number of primes strictly less than the requested number
+    pub count: u64,
+}
+
+// Serialize an integer as a JSON number, however large it is
+fn serialize_bigint<S>(n: &BigInt, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let n: Number = n.to_string().parse().map_err(serde::ser::Error::custom)?;
+    n.serialize(serializer)
+}
+
+fn serialize_opt_bigint<S>(n: &Option<BigInt>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match n {
+        Some(n) => serialize_bigint(n, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_opt_bigint<'de, D>(deserializer: D) -> Result<Option<BigInt>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<Number>::deserialize(deserializer)?
+        .map(|n| {
+            parse_integer(&n.to_string()).ok_or_else(|| D::Error::custom("expected an integer"))
+        })
+        .transpose()
+}
+
+// Serialize integers as JSON numbers, however large they are
+fn serialize_bigints<S>(numbers: &[BigInt], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::ser::SerializeSeq;
+
+    let mut seq = serializer.serialize_seq(Some(numbers.len()))?;
+    for n in numbers {
+        let n: Number = n.to_string().parse().map_err(serde::ser::Error::custom)?;
+        seq.serialize_element(&n)?;
+    }
+    seq.end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let big: BigInt = "529830422160613455916930483453466154480529308265681626708"
+            .parse()
+            .unwrap();
+
+        for number in [RequestNumber::BigInt(big), RequestNumber::Float(1.5)] {
+            let request = Request {
+                method: "isPrime".to_string(),
+                number,
+            };
+
+            let json = serde_json::to_string(&request).unwrap();
+            assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn test_request_serializes_bigint_as_number() {
+        let request = Request {
+            method: "isPrime".to_string(),
+            number: RequestNumber::BigInt(BigInt::from(7)),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"method":"isPrime","number":7}"#
+        );
+    }
+
+    #[test]
+    fn test_request_builder_round_trip() {
+        let big: BigInt = "-529830422160613455916930483453466154480529308265681626708"
+            .parse()
+            .unwrap();
+        let numbers = [
+            RequestNumber::BigInt(BigInt::from(0)),
+            RequestNumber::BigInt(big),
+            RequestNumber::Float(42.0),
+            RequestNumber::Float(-0.125),
+            RequestNumber::Float(1e300),
+            RequestNumber::Float(-0.0),
+            RequestNumber::Float(1e-300),
+        ];
+
+        for number in numbers {
+            let request = Request::new("isPrime", number);
+            let line = request.to_json_line();
+
+            assert_eq!(line.matches('\n').count(), 1, "{line}");
+            assert!(line.ends_with('\n'), "{line}");
+            assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn test_to_json_line() {
+        let line = Request::new("isPrime", RequestNumber::Float(7.0)).to_json_line();
+
+        assert_eq!(line, "{\"method\":\"isPrime\",\"number\":7.0}\n");
+    }
+
+    fn number(json: &str) -> RequestNumber {
+        let request = format!(r#"{{"method":"isPrime","number":{json}}}"#);
+        serde_json::from_str::<Request>(&request).unwrap().number
+    }
+
+    #[test]
+    fn test_scientific_notation_integers() {
+        let int = |n: i64| RequestNumber::BigInt(BigInt::from(n));
+
+        assert_eq!(number("1e10"), int(10_000_000_000));
+        assert_eq!(number("2e3"), int(2000));
+        assert_eq!(number("2.5e1"), int(25));
+        assert_eq!(number("1.50E1"), int(15));
+        assert_eq!(number("-3e2"), int(-300));
+        assert_eq!(number("1200e-2"), int(12));
+        assert_eq!(number("0e-5"), int(0));
+        assert_eq!(number("0e2000000000"), int(0));
+    }
+
+    #[test]
+    fn test_huge_exponent_is_rejected_quickly() {
+        let started = std::time::Instant::now();
+
+        for literal in ["1e200000000", "-2.5E+2000000000", "1e1000001"] {
+            let request = format!(r#"{{"method":"isPrime","number":{literal}}}"#);
+            assert!(
+                serde_json::from_str::<Request>(&request).is_err(),
+                "{literal}"
+            );
+        }
+
+        let response = r#"{"method":"isPrime","prime":false,"factor":1e200000000}"#;
+        assert!(serde_json::from_str::<Response>(response).is_err());
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_integer_float_boundary() {
+        assert_eq!(number("42"), RequestNumber::BigInt(BigInt::from(42)));
+        assert_eq!(number("42.0"), RequestNumber::Float(42.0));
+        assert_eq!(number("-0"), RequestNumber::BigInt(BigInt::from(0)));
+        assert_eq!(number("1e2"), RequestNumber::BigInt(BigInt::from(100)));
+    }
+
+    #[test]
+    fn test_scientific_notation_fractions() {
+        assert_eq!(number("2.5e0"), RequestNumber::Float(2.5));
+        assert_eq!(number("1.55e1"), RequestNumber::Float(15.5));
+        assert_eq!(number("1e-2"), RequestNumber::Float(0.01));
+
+        // without an exponent a decimal point still means a float
+        assert_eq!(number("7.0"), RequestNumber::Float(7.0));
+    }
+
+    #[test]
+    fn test_number_of_wrong_type() {
+        for (number, kind) in [
+            ("null", "null"),
+            ("true", "a boolean"),
+            ("[]", "an array"),
+            ("[1,2]", "an array"),
+            (r#""7""#, "a string"),
+            ("{}", "an object"),
+        ] {
+            let json = format!(r#"{{"method":"isPrime","number":{number}}}"#);
+            let error = serde_json::from_str::<Request>(&json).unwrap_err();
+
+            assert!(
+                error
+                    .to_string()
+                    .starts_with(&format!("number must be a JSON number, not {kind}")),
+                "{number}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_method_of_wrong_type() {
+        for (method, kind) in [("123", "a number"), ("null", "null"), ("[]", "an array")] {
+            let json = format!(r#"{{"method":{method},"number":7}}"#);
+            let error = serde_json::from_str::<Request>(&json).unwrap_err();
+
+            assert!(
+                error
+                    .to_string()
+                    .starts_with(&format!("method must be a string, not {kind}")),
+                "{method}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_integer_digits() {
+        assert_eq!(integer_digits("12345"), 5);
+        assert_eq!(integer_digits("-12345"), 5);
+        assert_eq!(integer_digits("12.5"), 2);
+        assert_eq!(integer_digits("0.5"), 0);
+        assert_eq!(integer_digits("1e3"), 4);
+        assert_eq!(integer_digits("1E+3"), 4);
+        assert_eq!(integer_digits("0.05e3"), 2);
+        assert_eq!(integer_digits("1e-3"), 0);
+        assert_eq!(integer_digits("0e5000"), 0);
+        assert_eq!(integer_digits("1e99999999999999999999"), u64::MAX);
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let json = r#"{"method":"isPrime","prime":true}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+
+        assert!(response.prime);
+        assert_eq!(response.factor, None);
+        assert_eq!(serde_json::to_string(&response).unwrap(), json);
+
+        let json = r#"{"method":"isPrime","prime":false,"factor":3}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+
+        assert_eq!(response.factor, Some(BigInt::from(3)));
+        assert_eq!(serde_json::to_string(&response).unwrap(), json);
+
+        let json = r#"{"method":"isPrime","prime":false,"number":7.0}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+
+        assert_eq!(response.number, Some(RequestNumber::Float(7.0)));
+        assert_eq!(serde_json::to_string(&response).unwrap(), json);
+
+        let json = r#"{"method":"isPrime","prime":true,"bits":3,"digits":1}"#;
+        let response: Response = serde_json::from_str(json).unwrap();
+
+        assert_eq!((response.bits, response.digits), (Some(3), Some(1)));
+        assert_eq!(serde_json::to_string(&response).unwrap(), json);
+    }
+}
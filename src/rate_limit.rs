@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+/// How many requests a single IP address may make
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Requests allowed per second on average
+    pub requests_per_second: f64,
+    /// Requests allowed in a burst before throttling kicks in
+    pub burst: u32,
+}
+
+// The tokens left for one IP address as of `updated`
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket rate limiter keyed by IP address, shared between
+/// connections
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter that applies `limit` to every address
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token for `ip`, returning how long the caller must wait before
+    /// the token is really available. Zero means the request can go ahead.
+    pub fn acquire(&self, ip: IpAddr) -> Duration {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.limit.burst as f64,
+            updated: now,
+        });
+
+        self.refill(bucket, now);
+
+        // the token is taken even if it has to be waited for, so concurrent
+        // requests queue up behind each other
+        bucket.tokens -= 1.0;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.limit.requests_per_second)
+        }
+    }
+
+    /// Take a token for `ip` only if one is available now, for callers that
+    /// drop requests over the limit instead of delaying them
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.limit.burst as f64,
+            updated: now,
+        });
+
+        self.refill(bucket, now);
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forget addresses whose buckets have refilled, they behave the same as
+    /// addresses that were never seen
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        buckets.retain(|_, bucket| {
+            self.refill(bucket, now);
+            bucket.tokens < self.limit.burst as f64
+        });
+    }
+
+    /// Number of addresses being tracked
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Whether no addresses are being tracked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+
+        bucket.tokens =
+            (bucket.tokens + elapsed * self.limit.requests_per_second).min(self.limit.burst as f64);
+        bucket.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_burst_then_throttle() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_second: 10.0,
+            burst: 3,
+        });
+
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(CLIENT), Duration::ZERO);
+        }
+
+        // the next tokens arrive every 100ms
+        let wait = limiter.acquire(CLIENT);
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+
+        let wait = limiter.acquire(CLIENT);
+        assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_try_acquire_refuses_over_the_limit() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_second: 1000.0,
+            burst: 2,
+        });
+
+        assert!(limiter.try_acquire(CLIENT));
+        assert!(limiter.try_acquire(CLIENT));
+        assert!(!limiter.try_acquire(CLIENT));
+
+        // refused requests don't use up tokens, so the next one arrives on
+        // time
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(limiter.try_acquire(CLIENT));
+    }
+
+    #[test]
+    fn test_addresses_are_limited_separately() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_second: 1.0,
+            burst: 1,
+        });
+
+        assert_eq!(limiter.acquire(CLIENT), Duration::ZERO);
+        assert_eq!(
+            limiter.acquire(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_cleanup_forgets_refilled_buckets() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_second: 1000.0,
+            burst: 1,
+        });
+
+        limiter.acquire(CLIENT);
+        assert_eq!(limiter.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(10));
+        limiter.cleanup();
+        assert!(limiter.is_empty());
+    }
+}
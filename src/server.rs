@@ -0,0 +1,3084 @@
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, BufWriter,
+    },
+    net::{TcpListener, TcpStream},
+    sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time::timeout,
+};
+use tracing::Instrument;
+
+use crate::{FramingMode, Metrics, PrimeTimeError, RequestHandler, ServerConfig};
+
+// Start the server
+pub async fn run(socket: SocketAddr) -> Result<(), PrimeTimeError> {
+    run_with_config(ServerConfig::builder().addr(socket).build()?).await
+}
+
+/// Start the server with the given configuration
+pub async fn run_with_config(config: ServerConfig) -> Result<(), PrimeTimeError> {
+    config.validate_lines()?;
+    let listeners = bind_shards(&config)?;
+
+    serve_all(
+        listeners,
+        config,
+        shutdown_signal(),
+        AcceptControl::default(),
+    )
+    .await
+}
+
+/// Start the server listening on every address in `addrs` at once, for
+/// example an IPv4 and an IPv6 address. The listeners share one sieve, cache
+/// and set of metrics.
+pub async fn run_multi(addrs: Vec<SocketAddr>) -> Result<(), PrimeTimeError> {
+    run_multi_with_config(addrs, ServerConfig::default()).await
+}
+
+/// Like [`run_multi`], with the given configuration. `config.addr` is
+/// ignored in favour of `addrs`.
+///
+/// Every address is bound before any connection is accepted. If one can't
+/// be bound the server doesn't start, and the error says which address
+/// failed.
+pub async fn run_multi_with_config(
+    addrs: Vec<SocketAddr>,
+    config: ServerConfig,
+) -> Result<(), PrimeTimeError> {
+    config.validate_lines()?;
+    let listeners = bind_all(&addrs, &config)?;
+
+    serve_all(
+        listeners,
+        config,
+        shutdown_signal(),
+        AcceptControl::default(),
+    )
+    .await
+}
+
+// Bind a listener to each address with the socket options from `config`
+fn bind_all(
+    addrs: &[SocketAddr],
+    config: &ServerConfig,
+) -> Result<Vec<TcpListener>, PrimeTimeError> {
+    if addrs.is_empty() {
+        return Err(PrimeTimeError::InvalidConfig(
+            "no addresses to listen on".to_string(),
+        ));
+    }
+
+    let mut listeners = Vec::new();
+    for &addr in addrs {
+        let config = ServerConfig {
+            addr,
+            ..config.clone()
+        };
+
+        match bind_shards(&config) {
+            Ok(shards) => listeners.extend(shards),
+            Err(PrimeTimeError::IOError(e)) => return Err(PrimeTimeError::BindFailed(addr, e)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(listeners)
+}
+
+/// Start the server in the background, running until `shutdown` resolves
+/// instead of until a signal is received. Useful when embedding the server in
+/// another program.
+///
+/// The returned handle can pause accepting connections and wait for the
+/// server to stop.
+pub async fn run_with_shutdown(
+    socket: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<ServerHandle, PrimeTimeError> {
+    spawn_server(ServerConfig::builder().addr(socket).build()?, shutdown)
+}
+
+/// Start the server with the given configuration in the background,
+/// returning once it is listening. Must be called from within a tokio
+/// runtime.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), prime_time::PrimeTimeError> {
+/// let config = prime_time::ServerConfig::builder()
+///     .addr("127.0.0.1:0".parse().unwrap())
+///     .build()?;
+///
+/// let server = prime_time::start(config)?;
+/// println!("Listening on {}", server.local_addr());
+///
+/// server.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn start(config: ServerConfig) -> Result<RunningServer, PrimeTimeError> {
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    // dropping the sender also stops the server
+    let handle = spawn_server(config, async {
+        let _ = stopped.await;
+    })?;
+
+    Ok(RunningServer { handle, stop })
+}
+
+// Bind to `config.addr` and serve on a new task until `shutdown` resolves
+fn spawn_server(
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<ServerHandle, PrimeTimeError> {
+    config.validate_lines()?;
+    let listeners = bind_shards(&config)?;
+    let local_addr = listeners[0].local_addr()?;
+
+    let accepting = AcceptControl::default();
+    let task = tokio::spawn(serve_all(
+        listeners,
+        config,
+        async {
+            shutdown.await;
+            Ok(())
+        },
+        accepting.clone(),
+    ));
+
+    Ok(ServerHandle {
+        local_addr,
+        accepting,
+        task,
+    })
+}
+
+/// A server running in the background, started by [`start`]. Dropping it
+/// shuts the server down without waiting for it.
+#[derive(Debug)]
+pub struct RunningServer {
+    handle: ServerHandle,
+    stop: oneshot::Sender<()>,
+}
+
+impl RunningServer {
+    /// The address the server is listening on, with the port the OS picked
+    /// if it was started on port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.local_addr()
+    }
+
+    /// The handle for pausing and resuming accepting connections
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+
+    /// Stop accepting connections, give those in flight
+    /// [`ServerConfig::shutdown_grace`] to finish, and wait for the server
+    /// to stop
+    pub async fn shutdown(self) -> Result<(), PrimeTimeError> {
+        let _ = self.stop.send(());
+        self.handle.wait().await
+    }
+}
+
+/// A server running in the background, started by [`run_with_shutdown`] or
+/// borrowed from a [`RunningServer`]
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    accepting: AcceptControl,
+    task: JoinHandle<Result<(), PrimeTimeError>>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting new connections, for maintenance. Connections already
+    /// accepted are still served, new ones wait in the listen backlog until
+    /// [`resume`](Self::resume) is called.
+    pub fn pause(&self) {
+        self.accepting.set_paused(true);
+    }
+
+    /// Accept connections again after [`pause`](Self::pause)
+    pub fn resume(&self) {
+        self.accepting.set_paused(false);
+    }
+
+    /// Whether accepting connections is paused
+    pub fn is_paused(&self) -> bool {
+        *self.accepting.paused.borrow()
+    }
+
+    /// Wait for the server to shut down
+    pub async fn wait(self) -> Result<(), PrimeTimeError> {
+        self.task.await?
+    }
+}
+
+// Whether the accept loop is taking new connections, shared between the
+// loop and whoever controls it
+#[derive(Clone, Debug)]
+pub(crate) struct AcceptControl {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for AcceptControl {
+    fn default() -> Self {
+        Self {
+            paused: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl AcceptControl {
+    fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+}
+
+/// Bind a listener to `socket`, returning it along with the address it is
+/// actually bound to. This is how to find the port the OS picked when binding
+/// to port 0.
+pub async fn bind(socket: SocketAddr) -> Result<(TcpListener, SocketAddr), PrimeTimeError> {
+    bind_with_config(&ServerConfig::builder().addr(socket).build()?)
+}
+
+/// Like [`bind`], but binds to `config.addr` using the socket options from
+/// `config`
+pub fn bind_with_config(
+    config: &ServerConfig,
+) -> Result<(TcpListener, SocketAddr), PrimeTimeError> {
+    let listener = TcpListener::from_std(listener_socket(config, config.addr, false)?)?;
+    let local_addr = listener.local_addr()?;
+
+    tracing::info!("Listening on {}", local_addr);
+
+    Ok((listener, local_addr))
+}
+
+// Bind `config.accept_shards` listeners to `config.addr` with SO_REUSEPORT,
+// for the kernel to spread connections across. Platforms without it get one.
+fn bind_shards(config: &ServerConfig) -> Result<Vec<TcpListener>, PrimeTimeError> {
+    if config.accept_shards == 1 || !cfg!(target_os = "linux") {
+        if config.accept_shards > 1 {
+            tracing::warn!("accept_shards needs SO_REUSEPORT, only one listener is opened");
+        }
+
+        let (listener, _) = bind_with_config(config)?;
+        return Ok(vec![listener]);
+    }
+
+    // the rest join whichever port the first was given, in case it was 0
+    let first = TcpListener::from_std(listener_socket(config, config.addr, true)?)?;
+    let local_addr = first.local_addr()?;
+
+    let mut listeners = vec![first];
+    for _ in 1..config.accept_shards {
+        listeners.push(TcpListener::from_std(listener_socket(
+            config, local_addr, true,
+        )?)?);
+    }
+
+    tracing::info!(shards = config.accept_shards, "Listening on {}", local_addr);
+
+    Ok(listeners)
+}
+
+// Create the listening socket by hand so options can be set before binding
+fn listener_socket(
+    config: &ServerConfig,
+    addr: SocketAddr,
+    reuse_port: bool,
+) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // the IPv6 wildcard only accepts IPv4 too if IPV6_V6ONLY is cleared
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(!config.dual_stack)?;
+    }
+
+    // match what tokio's TcpListener::bind does
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+
+    #[cfg(target_os = "linux")]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    // the socket API takes an int, anything bigger is capped anyway
+    socket.listen(i32::try_from(config.listen_backlog).unwrap_or(i32::MAX))?;
+
+    Ok(socket.into())
+}
+
+/// Accept connections on `listener` until SIGINT or SIGTERM is received
+pub async fn serve(listener: TcpListener, config: ServerConfig) -> Result<(), PrimeTimeError> {
+    serve_until(listener, config, shutdown_signal()).await
+}
+
+// Something connections can be accepted from, so TCP and Unix sockets can
+// share the serving logic
+pub(crate) trait Listener: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    // Accept a connection, along with the client's address if it has one
+    fn accept(
+        &self,
+    ) -> impl Future<Output = std::io::Result<(Self::Stream, Option<SocketAddr>)>> + Send;
+
+    // Apply per-connection options from the config
+    fn configure(_stream: &Self::Stream, _config: &ServerConfig) {}
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> std::io::Result<(TcpStream, Option<SocketAddr>)> {
+        let (stream, peer) = TcpListener::accept(self).await?;
+        Ok((stream, Some(peer)))
+    }
+
+    fn configure(stream: &TcpStream, config: &ServerConfig) {
+        configure_stream(stream, config);
+    }
+}
+
+// Accept connections until the shutdown future resolves
+pub(crate) async fn serve_until<L: Listener>(
+    listener: L,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+) -> Result<(), PrimeTimeError> {
+    serve_with_control(listener, config, shutdown, AcceptControl::default()).await
+}
+
+// Like serve_until, but accepting can be paused through `accepting`
+pub(crate) async fn serve_with_control<L: Listener>(
+    listener: L,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+    accepting: AcceptControl,
+) -> Result<(), PrimeTimeError> {
+    serve_all(vec![listener], config, shutdown, accepting).await
+}
+
+// Forget idle clients now and then so the rate limiter doesn't grow forever
+pub(crate) fn clean_up_rate_limiter(handler: &Arc<RequestHandler>) {
+    if handler.rate_limiter().is_none() {
+        return;
+    }
+
+    let handler = handler.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+
+            if let Some(limiter) = handler.rate_limiter() {
+                limiter.cleanup();
+            }
+        }
+    });
+}
+
+// What clients speak on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WireProtocol {
+    // JSON requests framed as the config says, newline delimited by default
+    Line,
+    // JSON requests as the bodies of HTTP requests
+    #[cfg(feature = "http")]
+    Http,
+}
+
+// Accept connections on every listener until the shutdown future resolves.
+// The listeners share one handler, so the sieve, cache and metrics are the
+// same whichever one a client connects to.
+pub(crate) async fn serve_all<L: Listener>(
+    listeners: Vec<L>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+    accepting: AcceptControl,
+) -> Result<(), PrimeTimeError> {
+    serve_protocol(listeners, config, shutdown, accepting, WireProtocol::Line).await
+}
+
+// Like serve_all, with clients speaking `protocol`
+pub(crate) async fn serve_protocol<L: Listener>(
+    listeners: Vec<L>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+    accepting: AcceptControl,
+    protocol: WireProtocol,
+) -> Result<(), PrimeTimeError> {
+    match protocol {
+        WireProtocol::Line => config.validate_lines()?,
+        #[cfg(feature = "http")]
+        WireProtocol::Http => config.validate()?,
+    }
+
+    // the config is shared by every connection
+    let config = Arc::new(config);
+
+    // the sieve and cache are shared by every connection
+    let handler = Arc::new(RequestHandler::new(&config));
+
+    // connections wait in the backlog until the cache is warm
+    if config.warm_cache_below > config.sieve_limit {
+        let started = Instant::now();
+        let warming = handler.clone();
+        let warmed = tokio::task::spawn_blocking(move || warming.warm_up()).await?;
+
+        tracing::info!(warmed, elapsed = ?started.elapsed(), "Warmed up the cache");
+    }
+
+    // each connection holds a permit for as long as it is being handled,
+    // whichever listener accepted it
+    let semaphore = Arc::new(Semaphore::new(config.max_connections));
+
+    clean_up_rate_limiter(&handler);
+
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = config.metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Serving metrics on {}", listener.local_addr()?);
+
+        tokio::spawn(crate::metrics::serve_metrics(
+            listener,
+            handler.metrics().clone(),
+        ));
+    }
+
+    // each listener gets its own accept loop, all stopped together
+    let (stop, stopping) = watch::channel(false);
+    let mut loops = JoinSet::new();
+    for listener in listeners {
+        loops.spawn(accept_loop(
+            listener,
+            protocol,
+            config.clone(),
+            handler.clone(),
+            semaphore.clone(),
+            accepting.clone(),
+            stopping.clone(),
+        ));
+    }
+
+    let mut stats_signal = stats_signal()?;
+
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            () = stats_requested(&mut stats_signal) => log_stats(handler.metrics()),
+            signal = &mut shutdown => {
+                signal?;
+
+                // every loop drains its own connections
+                stop.send_replace(true);
+                while let Some(stopped) = loops.join_next().await {
+                    stopped?;
+                }
+
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Accept connections on one listener until `stopping` is set, then give the
+// connections in flight a chance to finish
+async fn accept_loop<L: Listener>(
+    listener: L,
+    protocol: WireProtocol,
+    config: Arc<ServerConfig>,
+    handler: Arc<RequestHandler>,
+    semaphore: Arc<Semaphore>,
+    accepting: AcceptControl,
+    mut stopping: watch::Receiver<bool>,
+) {
+    // every connection task, so they can be waited for on shutdown
+    let mut connections = JoinSet::new();
+
+    let mut paused = accepting.paused.subscribe();
+
+    loop {
+        // read each time round, a change wakes the loop up
+        let accept_paused = *paused.borrow_and_update();
+
+        tokio::select! {
+            // reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            Ok(()) = paused.changed() => {
+                match *paused.borrow() {
+                    true => tracing::info!("Pausing accepting connections"),
+                    false => tracing::info!("Resuming accepting connections"),
+                }
+            }
+            (stream, peer, permit) = accept(&listener, &semaphore), if !accept_paused => {
+                L::configure(&stream, &config);
+
+                // create a span to contain all the logs for this connection,
+                // clients on a Unix socket don't have an address worth showing
+                let client = peer.map_or_else(|| "local".to_string(), |peer| peer.to_string());
+                let span = connection_span(&client);
+
+                let config = config.clone();
+                let handler = handler.clone();
+
+                connections.spawn(
+                    async move {
+                        let metrics = handler.metrics().clone();
+                        metrics.connection_opened();
+
+                        let peer = peer.map(|peer| peer.ip());
+                        let result = match protocol {
+                            WireProtocol::Line => hanndle_connection(stream, peer, config, handler).await,
+                            #[cfg(feature = "http")]
+                            WireProtocol::Http => {
+                                crate::http::handle_http_connection(stream, peer, config, handler)
+                                    .await
+                            }
+                        };
+
+                        metrics.connection_closed();
+                        drop(permit);
+                        result
+                    }
+                    .instrument(span),
+                );
+            }
+            // the sender going away means stopping too
+            () = stopped(&mut stopping) => {
+                tracing::info!(active_connections = connections.len(), "Shutting down");
+
+                // stop accepting, then give connections in flight a chance to
+                // finish
+                drop(listener);
+                drain(connections, config.shutdown_grace).await;
+
+                return;
+            }
+        }
+    }
+}
+
+// Resolves once the accept loops are told to stop, or whatever tells them
+// has gone away
+async fn stopped(stopping: &mut watch::Receiver<bool>) {
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+// Operators can ask for the counters to be logged by sending SIGUSR1. Other
+// platforms have no such signal and never ask.
+#[cfg(unix)]
+type StatsSignal = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type StatsSignal = ();
+
+fn stats_signal() -> std::io::Result<StatsSignal> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        signal(SignalKind::user_defined1())
+    }
+
+    #[cfg(not(unix))]
+    Ok(())
+}
+
+// Resolve when the counters should be logged
+async fn stats_requested(signal: &mut StatsSignal) {
+    #[cfg(unix)]
+    if signal.recv().await.is_some() {
+        return;
+    }
+
+    // there won't be another signal
+    let _ = signal;
+    std::future::pending().await
+}
+
+fn log_stats(metrics: &Metrics) {
+    tracing::info!(
+        requests = metrics.requests(),
+        primes = metrics.primes(),
+        composites = metrics.composites(),
+        malformed = metrics.malformed(),
+        active_connections = metrics.active_connections(),
+        uptime = ?metrics.uptime(),
+        "Stats"
+    );
+}
+
+// Numbers connections in the order they were accepted, across every listener
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// The span a connection's logs are recorded in. Each connection gets its own
+// id, so one client's logs can be picked out of the interleaved lines even
+// when it shares an address with others. The request count and how long the
+// connection lived are filled in when it closes.
+pub(crate) fn connection_span(client: &str) -> tracing::Span {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+
+    tracing::info_span!(
+        "Connection",
+        id,
+        client = %client,
+        requests = tracing::field::Empty,
+        duration = tracing::field::Empty,
+    )
+}
+
+// Wait up to `grace` for the connections to finish, then abort the rest
+async fn drain(mut connections: JoinSet<Result<(), PrimeTimeError>>, grace: Duration) {
+    let mut completed = 0;
+
+    let finished = timeout(grace, async {
+        while connections.join_next().await.is_some() {
+            completed += 1;
+        }
+    })
+    .await;
+
+    let aborted = connections.len();
+    if finished.is_err() {
+        connections.shutdown().await;
+    }
+
+    tracing::info!(completed, aborted, "Connections drained");
+}
+
+// Wait for a free connection slot, then accept the next connection. Errors
+// from accepting only affect the connection being accepted, so they are
+// logged and accepting carries on.
+async fn accept<L: Listener>(
+    listener: &L,
+    semaphore: &Arc<Semaphore>,
+) -> (L::Stream, Option<SocketAddr>, OwnedSemaphorePermit) {
+    let permit = semaphore
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("connection semaphore is never closed");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => return (stream, peer, permit),
+            Err(e) => {
+                tracing::warn!("Failed to accept connection: {}", e);
+
+                if let Some(delay) = accept_backoff(&e) {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+// Apply the per-connection socket options. Failing to set one isn't worth
+// dropping the connection over.
+fn configure_stream(stream: &TcpStream, config: &ServerConfig) {
+    if config.tcp_nodelay {
+        if let Err(e) = stream.set_nodelay(true) {
+            tracing::warn!("Failed to set TCP_NODELAY: {}", e);
+        }
+    }
+
+    if let Some(time) = config.tcp_keepalive {
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive(time, config)) {
+            tracing::warn!("Failed to set SO_KEEPALIVE: {}", e);
+        }
+    }
+}
+
+// Keepalive settings starting probes after `time`, with the configured
+// interval on platforms that support setting it
+fn keepalive(time: Duration, config: &ServerConfig) -> TcpKeepalive {
+    let keepalive = TcpKeepalive::new().with_time(time);
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let keepalive = match config.tcp_keepalive_interval {
+        Some(interval) => keepalive.with_interval(interval),
+        None => keepalive,
+    };
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "windows",
+    )))]
+    let _ = config;
+
+    keepalive
+}
+
+// How long to wait before accepting again after an error. Errors caused by
+// the client going away can be retried straight away, anything else (like
+// running out of file descriptors) needs time to clear up.
+fn accept_backoff(error: &std::io::Error) -> Option<Duration> {
+    use std::io::ErrorKind;
+
+    match error.kind() {
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted => None,
+        _ => Some(Duration::from_millis(100)),
+    }
+}
+
+// Wait for SIGINT, or SIGTERM on Unix
+pub(crate) async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+// Handle a connection over any byte stream, so plain TCP, TLS and in-memory
+// streams can all share the same logic
+async fn hanndle_connection<S>(
+    stream: S,
+    peer: Option<IpAddr>,
+    config: Arc<ServerConfig>,
+    handler: Arc<RequestHandler>,
+) -> Result<(), PrimeTimeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+
+    // a buffered reader is required to read line by line
+    let mut buf_reader = BufReader::new(&mut reader);
+
+    // behind a load balancer the real client is given in a header first
+    let peer = if config.proxy_protocol {
+        let header = timeout(config.idle_timeout, read_proxy_header(&mut buf_reader)).await;
+
+        match header {
+            Ok(Ok(Some(client))) => {
+                tracing::Span::current().record("client", tracing::field::display(client));
+                Some(client.ip())
+            }
+            Ok(Ok(None)) => peer,
+            Ok(Err(e)) => {
+                tracing::info!("Bad PROXY header, disconnecting: {}", e);
+                return Ok(());
+            }
+            Err(_) => {
+                tracing::info!("No PROXY header in time, disconnecting");
+                return Ok(());
+            }
+        }
+    } else {
+        peer
+    };
+
+    tracing::info!("Connected");
+
+    // responses to pipelined requests are written out together
+    let mut writer = BufWriter::new(writer);
+
+    // buffers for request lines, reused for every request on the connection
+    let mut spare: Vec<String> = Vec::new();
+
+    // summarised when the connection closes, to spot heavy clients
+    let connected_at = Instant::now();
+    let mut requests: u64 = 0;
+
+    // requests read so far, to pick which ones are logged
+    let mut received: u64 = 0;
+
+    let result = 'connection: loop {
+        // wait for the next request, giving up if the client idles. A new
+        // connection may have less time to send its first one.
+        let wait = match config.first_request_timeout {
+            Some(first) if received == 0 => first.min(config.idle_timeout),
+            _ => config.idle_timeout,
+        };
+        let mut line = spare.pop().unwrap_or_default();
+        let read = timeout(wait, read_request(&mut buf_reader, &mut line, &config)).await;
+
+        let first = match read {
+            Ok(Ok(ReadRequest::Line(read))) => read,
+            Ok(Ok(ReadRequest::Disconnected)) => {
+                tracing::info!("Disconnected");
+                break Ok(());
+            }
+            Ok(Err(e)) => break Err(e.into()),
+            Err(_) if received == 0 && config.first_request_timeout.is_some() => {
+                tracing::info!("No request in time, disconnecting");
+                break Ok(());
+            }
+            Err(_) => {
+                tracing::info!("Idle timeout, disconnecting");
+                break Ok(());
+            }
+        };
+
+        // start on every request that has already arrived along with it, so
+        // pipelined requests are answered concurrently
+        let mut batch = vec![(first, line)];
+        while request_buffered(buf_reader.buffer(), &config) {
+            let mut line = spare.pop().unwrap_or_default();
+
+            // the whole line is buffered, so this doesn't wait on the client
+            match read_request(&mut buf_reader, &mut line, &config).await {
+                Ok(ReadRequest::Line(read)) => batch.push((read, line)),
+                Ok(ReadRequest::Disconnected) => break,
+                Err(e) => break 'connection Err(e.into()),
+            }
+        }
+
+        let mut pending = Vec::with_capacity(batch.len());
+        for (read, line) in batch {
+            // slow down clients sending requests too quickly
+            if let (Some(limiter), Some(ip)) = (handler.rate_limiter(), peer) {
+                let wait = limiter.acquire(ip);
+
+                if !wait.is_zero() {
+                    tracing::debug!(?wait, "Rate limited");
+                    tokio::time::sleep(wait).await;
+                }
+            }
+
+            let sampled = received.is_multiple_of(config.log_sample_rate);
+            received += 1;
+
+            let answer = match read {
+                Ok(()) => {
+                    if sampled {
+                        tracing::info!(
+                            received = ?line.trim_end_matches(['\r', '\n', char::from(config.delimiter)])
+                        );
+                    }
+                    Ok(spawn_answer(&handler, line, config.request_timeout))
+                }
+                Err(e) => {
+                    spare.push(line);
+                    Err(e)
+                }
+            };
+            pending.push((sampled, answer));
+        }
+
+        // answer in the order the requests arrived, whichever finishes first
+        let mut close = false;
+        let mut limit_reached = false;
+        let mut pending = pending.into_iter();
+        while let Some((sampled, answer)) = pending.next() {
+            // requests past the limit go unanswered
+            if let Some(max) = config.max_requests_per_connection {
+                if requests >= max as u64 {
+                    limit_reached = true;
+                    break;
+                }
+            }
+
+            let result = match answer {
+                Ok(task) => {
+                    let answered = if config.cancel_on_disconnect {
+                        answer_unless_closed(task, &mut buf_reader).await
+                    } else {
+                        Some(task.await)
+                    };
+
+                    match answered {
+                        Some(Ok((line, result))) => {
+                            spare.push(line);
+                            result
+                        }
+                        Some(Err(e)) => Err(e.into()),
+                        None => {
+                            tracing::info!("Client disconnected before being answered");
+                            abort_pending(pending);
+                            break 'connection Ok(());
+                        }
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            requests += 1;
+
+            // a malformed request ends the connection, a slow one only does
+            // if configured to
+            let (response, log) = match result {
+                Ok(r) => (r, sampled),
+                Err(e @ PrimeTimeError::Timeout(_)) => {
+                    tracing::warn!("{}", e);
+                    close = config.close_on_request_timeout;
+                    (handler.error_line(&e), true)
+                }
+                Err(e) => {
+                    tracing::info!("Bad request: {}", e);
+                    handler.metrics().record_malformed(&e);
+                    close = true;
+                    (handler.error_line(&e), true)
+                }
+            };
+
+            if log {
+                tracing::info!(sending = ?response);
+            }
+
+            // a client that can't be written to won't see the rest of the
+            // batch either, and there's no use reading more from it
+            let write = write_response(&mut writer, &response, &config);
+            if let Err(e) = write_within(config.write_timeout, write).await {
+                log_write_error(&e);
+                abort_pending(pending);
+                break 'connection Ok(());
+            }
+
+            // requests after the one closing the connection go unanswered
+            if close {
+                break;
+            }
+        }
+
+        // the client won't see answers to the rest of the batch
+        abort_pending(pending);
+
+        // one flush for the whole batch
+        if let Err(e) = write_within(config.write_timeout, writer.flush()).await {
+            log_write_error(&e);
+            break Ok(());
+        }
+
+        if close {
+            tracing::info!("Bad request, disconnecting");
+            break Ok(());
+        }
+
+        if limit_reached {
+            tracing::info!("Request limit reached, disconnecting");
+            break Ok(());
+        }
+    };
+
+    let span = tracing::Span::current();
+    span.record("requests", requests);
+    span.record(
+        "duration",
+        format!("{:.1}s", connected_at.elapsed().as_secs_f64()),
+    );
+    tracing::info!("Connection closed");
+
+    result
+}
+
+// The longest PROXY protocol v1 header, including its CRLF
+const MAX_PROXY_HEADER_BYTES: u64 = 107;
+
+// Read the PROXY protocol v1 header a load balancer sends before the client's
+// requests, returning the client's address. `None` means the balancer didn't
+// know it, as with `PROXY UNKNOWN`.
+async fn read_proxy_header<R>(reader: &mut R) -> Result<Option<SocketAddr>, PrimeTimeError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut header = Vec::new();
+    reader
+        .take(MAX_PROXY_HEADER_BYTES)
+        .read_until(b'\n', &mut header)
+        .await?;
+
+    match std::str::from_utf8(&header) {
+        Ok(header) => parse_proxy_header(header),
+        Err(_) => Err(PrimeTimeError::MalformedRequest(
+            "PROXY header is not ASCII".to_string(),
+        )),
+    }
+}
+
+// Parse a PROXY protocol v1 header like
+// `PROXY TCP4 203.0.113.7 10.0.0.1 56324 8080\r\n`, giving the source
+// address
+fn parse_proxy_header(header: &str) -> Result<Option<SocketAddr>, PrimeTimeError> {
+    let invalid =
+        |reason: &str| PrimeTimeError::MalformedRequest(format!("invalid PROXY header, {reason}"));
+
+    let header = header
+        .strip_suffix("\r\n")
+        .ok_or_else(|| invalid("not terminated by CRLF"))?;
+    let fields: Vec<&str> = header.split(' ').collect();
+
+    let (family, source, destination, source_port, destination_port) = match fields[..] {
+        // whatever follows UNKNOWN is ignored
+        ["PROXY", "UNKNOWN", ..] => return Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            (family, source, destination, source_port, destination_port)
+        }
+        ["PROXY", ..] => return Err(invalid("expected TCP4, TCP6 or UNKNOWN and four fields")),
+        _ => return Err(invalid("missing the PROXY signature")),
+    };
+
+    let address = |field: &str| match field.parse::<IpAddr>() {
+        Ok(ip) if ip.is_ipv4() == (family == "TCP4") => Ok(ip),
+        _ => Err(invalid(&format!("bad {family} address `{field}`"))),
+    };
+    // decimal without a sign, which u16's parser would allow
+    let port = |field: &str| match field.parse::<u16>() {
+        Ok(port) if field.bytes().all(|b| b.is_ascii_digit()) => Ok(port),
+        _ => Err(invalid(&format!("bad port `{field}`"))),
+    };
+
+    let source = SocketAddr::new(address(source)?, port(source_port)?);
+    address(destination)?;
+    port(destination_port)?;
+
+    Ok(Some(source))
+}
+
+// Stop working on requests whose answers won't be sent
+fn abort_pending<E>(pending: impl Iterator<Item = (bool, Result<JoinHandle<Answered>, E>)>) {
+    for (_, answer) in pending {
+        if let Ok(task) = answer {
+            task.abort();
+        }
+    }
+}
+
+// What reading a line from the client gave
+enum ReadRequest {
+    // a request line was read into the buffer, or the reason it can't be
+    // answered
+    Line(Result<(), PrimeTimeError>),
+    Disconnected,
+}
+
+// Read the next request from the client into `line`, which is cleared
+// first. Requests that are too long, cut off or aren't UTF-8 can't be
+// answered, but the connection is still usable to say so.
+async fn read_request<R>(
+    reader: &mut R,
+    line: &mut String,
+    config: &ServerConfig,
+) -> std::io::Result<ReadRequest>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+
+    match config.framing {
+        FramingMode::NewlineDelimited => read_line(reader, line, config).await,
+        FramingMode::LengthPrefixed => read_frame(reader, line, config).await,
+    }
+}
+
+async fn read_line<R>(
+    reader: &mut R,
+    line: &mut String,
+    config: &ServerConfig,
+) -> std::io::Result<ReadRequest>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let max_line_bytes = config.max_line_bytes;
+    let mut limited = reader.take(max_line_bytes as u64);
+
+    // read into the line's buffer so it is reused, checking it is UTF-8 once
+    // the whole line is in
+    let mut bytes = std::mem::take(line).into_bytes();
+    let read = match limited.read_until(config.delimiter, &mut bytes).await? {
+        // if no bytes were read, the client disconnected
+        0 => return Ok(ReadRequest::Disconnected),
+        _ if bytes.last() == Some(&config.delimiter) => ReadRequest::Line(Ok(())),
+        // a line that hits the limit without a delimiter is too long
+        bytes_read if bytes_read == max_line_bytes => {
+            ReadRequest::Line(Err(PrimeTimeError::LineTooLong(max_line_bytes)))
+        }
+        // otherwise the client hung up part way through the line, which
+        // might parse but isn't the request they meant to send
+        _ => unterminated(config),
+    };
+
+    match String::from_utf8(bytes) {
+        Ok(bytes) => {
+            *line = bytes;
+            Ok(read)
+        }
+        // bytes that aren't UTF-8 can't be JSON, so this is a malformed
+        // request rather than a broken connection
+        Err(_) => Ok(ReadRequest::Line(Err(PrimeTimeError::MalformedRequest(
+            "request is not valid UTF-8".to_string(),
+        )))),
+    }
+}
+
+// Wait for an answer, giving up on it if the client closes the connection
+// first. The answer can't stop the blocking thread it runs on, but the
+// connection, its buffers and its slot are released straight away.
+async fn answer_unless_closed<R>(
+    mut task: JoinHandle<Answered>,
+    reader: &mut R,
+) -> Option<Result<Answered, tokio::task::JoinError>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    tokio::select! {
+        answered = &mut task => Some(answered),
+        _ = client_closed(reader) => {
+            task.abort();
+            None
+        }
+    }
+}
+
+// Resolves once the client closes the connection. Requests sent meanwhile
+// are left buffered for the next read, and once there are some a close
+// can't be seen past them, so this never resolves.
+async fn client_closed<R>(reader: &mut R)
+where
+    R: AsyncBufRead + Unpin,
+{
+    match reader.fill_buf().await {
+        Ok([]) | Err(_) => (),
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+// A request line handed back for reuse, along with the response to it
+type Answered = (String, Result<String, PrimeTimeError>);
+
+// Read a length-prefixed frame. The whole frame has to be valid UTF-8 since
+// it holds a single request.
+async fn read_frame<R>(
+    reader: &mut R,
+    line: &mut String,
+    config: &ServerConfig,
+) -> std::io::Result<ReadRequest>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // closing between frames is an ordinary disconnect
+    if reader.fill_buf().await?.is_empty() {
+        return Ok(ReadRequest::Disconnected);
+    }
+
+    let mut prefix = [0; 4];
+    if let Err(e) = reader.read_exact(&mut prefix).await {
+        return match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Ok(unterminated(config)),
+            _ => Err(e),
+        };
+    }
+
+    // the rest of a frame that's too long isn't read, the connection is
+    // closed before it would be
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > config.max_line_bytes {
+        return Ok(ReadRequest::Line(Err(PrimeTimeError::LineTooLong(
+            config.max_line_bytes,
+        ))));
+    }
+
+    // read into the line's buffer so it is reused like it is for lines
+    let mut payload = std::mem::take(line).into_bytes();
+    payload.resize(len, 0);
+    if let Err(e) = reader.read_exact(&mut payload).await {
+        return match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Ok(unterminated(config)),
+            _ => Err(e),
+        };
+    }
+
+    match String::from_utf8(payload) {
+        Ok(payload) => {
+            *line = payload;
+            Ok(ReadRequest::Line(Ok(())))
+        }
+        Err(_) => Ok(ReadRequest::Line(Err(PrimeTimeError::MalformedRequest(
+            "request is not valid UTF-8".to_string(),
+        )))),
+    }
+}
+
+// What to do with a request the client hung up part way through
+fn unterminated(config: &ServerConfig) -> ReadRequest {
+    if config.drop_unterminated_line {
+        tracing::info!("Dropping unterminated request");
+        return ReadRequest::Disconnected;
+    }
+
+    ReadRequest::Line(Err(PrimeTimeError::MalformedRequest(
+        "connection closed part way through a request".to_string(),
+    )))
+}
+
+// Whether a whole request is waiting in the read buffer, so reading it won't
+// wait on the client
+fn request_buffered(buffer: &[u8], config: &ServerConfig) -> bool {
+    match config.framing {
+        FramingMode::NewlineDelimited => buffer.contains(&config.delimiter),
+        FramingMode::LengthPrefixed => match buffer {
+            [a, b, c, d, payload @ ..] => {
+                payload.len() >= u32::from_be_bytes([*a, *b, *c, *d]) as usize
+            }
+            _ => false,
+        },
+    }
+}
+
+// Write a response in the connection's framing
+async fn write_response<W>(
+    writer: &mut W,
+    response: &str,
+    config: &ServerConfig,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    match config.framing {
+        FramingMode::NewlineDelimited => {
+            writer.write_all(response.as_bytes()).await?;
+            writer.write_all(&[config.delimiter]).await
+        }
+        FramingMode::LengthPrefixed => {
+            let len = u32::try_from(response.len()).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "response too long to frame",
+                )
+            })?;
+
+            writer.write_all(&len.to_be_bytes()).await?;
+            writer.write_all(response.as_bytes()).await
+        }
+    }
+}
+
+// A client closing its connection, or shutting down its reading side, before
+// reading every response is normal, only other failures are worth an error
+fn log_write_error(e: &std::io::Error) {
+    use std::io::ErrorKind;
+
+    match e.kind() {
+        ErrorKind::BrokenPipe
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected => {
+            tracing::debug!("Client went away before reading its responses: {}", e)
+        }
+        ErrorKind::TimedOut => tracing::info!("Failed to write to socket: {}", e),
+        _ => tracing::error!("Failed to write to socket: {}", e),
+    }
+}
+
+// Give up on a write that takes longer than `limit`, the client has most
+// likely stopped reading and left the socket buffer full
+async fn write_within(
+    limit: Option<Duration>,
+    write: impl Future<Output = std::io::Result<()>>,
+) -> std::io::Result<()> {
+    let Some(limit) = limit else {
+        return write.await;
+    };
+
+    timeout(limit, write).await.unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("client hasn't read its responses for {limit:?}"),
+        ))
+    })
+}
+
+// Answer a request line in its own task so several can be worked on at once
+fn spawn_answer(
+    handler: &Arc<RequestHandler>,
+    mut line: String,
+    request_timeout: Option<Duration>,
+) -> JoinHandle<Answered> {
+    let handler = handler.clone();
+
+    tokio::spawn(
+        async move {
+            let result = handle_line(&handler, &mut line, request_timeout).await;
+            (line, result)
+        }
+        .in_current_span(),
+    )
+}
+
+// Answer a request line on the handler's compute pool. Checking a large
+// number is CPU-bound and would otherwise stall every connection sharing the
+// runtime worker. On timeout the connection stops waiting, but the worker
+// still runs to completion since synchronous code can't be interrupted.
+//
+// The line is handed back once answered so its buffer can be reused, after a
+// timeout it is left empty.
+pub(crate) async fn handle_line(
+    handler: &Arc<RequestHandler>,
+    line: &mut String,
+    request_timeout: Option<Duration>,
+) -> Result<String, PrimeTimeError> {
+    let handler = handler.clone();
+    let request = std::mem::take(line);
+
+    // keep the connection's span, so logs from answering are recorded in it
+    let span = tracing::Span::current();
+    let pool = handler.clone();
+    let task = pool.pool().run(move || {
+        let _entered = span.enter();
+        let result = handler.handle(&request);
+        (request, result)
+    });
+
+    let (request, result) = match request_timeout {
+        Some(budget) => match timeout(budget, task).await {
+            Ok(answered) => answered?,
+            Err(_) => return Err(PrimeTimeError::Timeout(budget)),
+        },
+        None => task.await?,
+    };
+
+    *line = request;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Captured, TestServer},
+        RateLimit,
+    };
+    use num_bigint::BigInt;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{DuplexStream, ReadBuf};
+
+    #[test]
+    fn test_accept_backoff() {
+        use std::io::{Error, ErrorKind};
+
+        // the client hanging up doesn't need a pause
+        assert!(accept_backoff(&Error::from(ErrorKind::ConnectionAborted)).is_none());
+
+        // running out of file descriptors does (EMFILE)
+        assert!(accept_backoff(&Error::from_raw_os_error(24)).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_configure_stream_nodelay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for enabled in [true, false] {
+            let client = TcpStream::connect(addr);
+            let (accepted, _client) = tokio::join!(listener.accept(), client);
+            let (stream, _) = accepted.unwrap();
+
+            let config = ServerConfig::builder()
+                .tcp_nodelay(enabled)
+                .build()
+                .unwrap();
+            configure_stream(&stream, &config);
+
+            assert_eq!(stream.nodelay().unwrap(), enabled);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configure_stream_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for keepalive in [None, Some(Duration::from_secs(7))] {
+            let client = TcpStream::connect(addr);
+            let (accepted, _client) = tokio::join!(listener.accept(), client);
+            let (stream, _) = accepted.unwrap();
+
+            let config = ServerConfig {
+                tcp_keepalive: keepalive,
+                tcp_keepalive_interval: Some(Duration::from_secs(3)),
+                ..Default::default()
+            };
+            configure_stream(&stream, &config);
+
+            let socket = SockRef::from(&stream);
+            assert_eq!(socket.keepalive().unwrap(), keepalive.is_some());
+
+            #[cfg(target_os = "linux")]
+            if keepalive.is_some() {
+                assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(7));
+                assert_eq!(
+                    socket.tcp_keepalive_interval().unwrap(),
+                    Duration::from_secs(3)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_with_shutdown() {
+        // find a free port to run on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = run_with_shutdown(addr, async {
+            let _ = signal.await;
+        })
+        .await
+        .unwrap();
+        assert_eq!(server.local_addr(), addr);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+        let mut buf = [0; 64];
+        assert!(client.read(&mut buf).await.unwrap() > 0);
+        drop(client);
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server.wait())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_multiple_addresses() {
+        let addrs = [
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ];
+        let listeners = bind_all(&addrs, &ServerConfig::default()).unwrap();
+        let bound: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_ne!(bound[0], bound[1]);
+
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_all(
+            listeners,
+            ServerConfig {
+                stats_method: true,
+                ..Default::default()
+            },
+            async {
+                let _ = signal.await;
+                Ok(())
+            },
+            AcceptControl::default(),
+        ));
+
+        let mut buf = [0; 256];
+        for addr in &bound {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+                .await
+                .unwrap();
+            let len = client.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"{\"method\":\"isPrime\",\"prime\":true}\n");
+        }
+
+        // both listeners count towards the same metrics
+        let mut client = BufReader::new(TcpStream::connect(bound[1]).await.unwrap());
+        client.write_all(b"{\"method\":\"stats\"}\n").await.unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(stats["requests"], 2);
+        drop(client);
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_accept_shards_share_a_port() {
+        let config = ServerConfig::builder()
+            .addr("127.0.0.1:0".parse().unwrap())
+            .accept_shards(3)
+            .build()
+            .unwrap();
+        let listeners = bind_shards(&config).unwrap();
+
+        assert_eq!(listeners.len(), 3);
+        let addr = listeners[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_all(
+            listeners,
+            config,
+            async {
+                let _ = signal.await;
+                Ok(())
+            },
+            AcceptControl::default(),
+        ));
+
+        // whichever shard the kernel picks answers
+        let mut buf = [0; 64];
+        for _ in 0..6 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+            assert!(client.read(&mut buf).await.unwrap() > 0);
+        }
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_all_names_the_failed_address() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().map(|addr| (taken, addr)).unwrap();
+        let addrs = ["127.0.0.1:0".parse().unwrap(), taken.1];
+
+        let result = bind_all(&addrs, &ServerConfig::default());
+        assert!(
+            matches!(&result, Err(PrimeTimeError::BindFailed(addr, _)) if *addr == taken.1),
+            "{result:?}"
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains(&taken.1.to_string()));
+
+        assert!(matches!(
+            bind_all(&[], &ServerConfig::default()),
+            Err(PrimeTimeError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let server = start(
+            ServerConfig::builder()
+                .addr("127.0.0.1:0".parse().unwrap())
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_ne!(server.local_addr().port(), 0);
+
+        let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+        client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+        let mut buf = [0; 64];
+        assert!(client.read(&mut buf).await.unwrap() > 0);
+        drop(client);
+
+        tokio::time::timeout(Duration::from_secs(5), server.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_start_rejects_invalid_config() {
+        // the fields are public, so a config can skip the builder's checks
+        for config in [
+            ServerConfig {
+                compute_workers: 0,
+                ..ServerConfig::default()
+            },
+            ServerConfig {
+                max_connections: 0,
+                ..ServerConfig::default()
+            },
+            ServerConfig {
+                miller_rabin_rounds: Some(0),
+                ..ServerConfig::default()
+            },
+        ] {
+            let config = ServerConfig {
+                addr: "127.0.0.1:0".parse().unwrap(),
+                ..config
+            };
+
+            assert!(matches!(
+                start(config.clone()),
+                Err(PrimeTimeError::InvalidConfig(_))
+            ));
+            assert!(matches!(
+                run_with_config(config).await,
+                Err(PrimeTimeError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pause_accepting() {
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = run_with_shutdown("127.0.0.1:0".parse().unwrap(), async {
+            let _ = signal.await;
+        })
+        .await
+        .unwrap();
+        let request = b"{\"method\":\"ping\"}\n";
+        let mut buf = [0; 64];
+
+        // a connection made before pausing carries on being served
+        let mut before = TcpStream::connect(server.local_addr()).await.unwrap();
+        before.write_all(request).await.unwrap();
+        assert!(before.read(&mut buf).await.unwrap() > 0);
+
+        server.pause();
+        assert!(server.is_paused());
+
+        // the OS still queues the new connection, but nothing answers it
+        let mut during = TcpStream::connect(server.local_addr()).await.unwrap();
+        during.write_all(request).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_millis(200), during.read(&mut buf)).await;
+        assert!(read.is_err(), "answered while paused");
+
+        before.write_all(request).await.unwrap();
+        assert!(before.read(&mut buf).await.unwrap() > 0);
+
+        // once resumed the queued connection is accepted and answered
+        server.resume();
+        assert!(during.read(&mut buf).await.unwrap() > 0);
+
+        drop((before, during));
+        shutdown.send(()).unwrap();
+        server.wait().await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigusr1_logs_stats() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        let server = TestServer::spawn(ServerConfig::default()).await;
+
+        // once a request is answered the server is listening for the signal
+        let mut lines = BufReader::new(server.connect().await).lines();
+        lines
+            .get_mut()
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        lines.next_line().await.unwrap().unwrap();
+
+        let status = std::process::Command::new("kill")
+            .args(["-USR1", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let stats = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let stats = captured
+                    .output()
+                    .lines()
+                    .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                    .find(|line| line["fields"]["message"] == "Stats");
+
+                match stats {
+                    Some(stats) => break stats,
+                    None => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("stats are logged");
+
+        assert_eq!(stats["level"], "INFO");
+        assert_eq!(stats["fields"]["primes"], 1);
+        assert_eq!(stats["fields"]["active_connections"], 1);
+
+        drop(lines);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_bind_reports_ephemeral_port() {
+        let (listener, addr) = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        assert_ne!(addr.port(), 0);
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_listen_backlog() {
+        // values past what the kernel allows are capped rather than refused
+        for backlog in [1, 16, u32::MAX] {
+            let config = ServerConfig::builder()
+                .addr("127.0.0.1:0".parse().unwrap())
+                .listen_backlog(backlog)
+                .build()
+                .unwrap();
+            let (listener, addr) = bind_with_config(&config).unwrap();
+
+            // a connection waits in the backlog until it is accepted
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_accepts_ipv4() {
+        let config = ServerConfig::builder()
+            .addr("[::]:0".parse().unwrap())
+            .dual_stack(true)
+            .build()
+            .unwrap();
+        let (listener, addr) = bind_with_config(&config).unwrap();
+
+        let client = TcpStream::connect(("127.0.0.1", addr.port()));
+        let (accepted, _) = tokio::join!(listener.accept(), client);
+
+        let (_, peer) = accepted.unwrap();
+        assert!(peer.is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_rejects_ipv4() {
+        let config = ServerConfig::builder()
+            .addr("[::]:0".parse().unwrap())
+            .dual_stack(false)
+            .build()
+            .unwrap();
+        let (_listener, addr) = bind_with_config(&config).unwrap();
+
+        assert!(TcpStream::connect(("127.0.0.1", addr.port()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = ServerConfig {
+            max_connections: 1,
+            ..Default::default()
+        };
+        tokio::spawn(serve_until(listener, config, std::future::pending()));
+
+        let request = b"{\"method\":\"isPrime\",\"number\":7}\n";
+        let mut buf = [0; 64];
+
+        // the first connection takes the only slot
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(request).await.unwrap();
+        assert!(first.read(&mut buf).await.unwrap() > 0);
+
+        // the second connection has to wait for it
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(request).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(200), second.read(&mut buf)).await;
+        assert!(waiting.is_err());
+
+        // closing the first connection lets the second one through
+        drop(first);
+        assert!(second.read(&mut buf).await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finishes_requests_in_flight() {
+        let server = TestServer::spawn(ServerConfig::default()).await;
+        let addr = server.addr;
+
+        let mut client = server.connect().await;
+        client
+            .write_all(slow_request(1279).as_bytes())
+            .await
+            .unwrap();
+
+        // give the server a moment to start on the request
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let server = server.shutdown();
+
+        let mut lines = BufReader::new(client).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+        drop(lines);
+
+        // the listener is gone once the server has stopped
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_after_grace() {
+        let server = TestServer::spawn(ServerConfig {
+            shutdown_grace: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await;
+
+        // an idle connection would otherwise hold on until the idle timeout
+        let mut client = server.connect().await;
+        client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+        let mut buf = [0; 64];
+        assert!(client.read(&mut buf).await.unwrap() > 0);
+
+        let start = std::time::Instant::now();
+        server.shutdown().await.unwrap().unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_request_split_across_writes() {
+        let server = TestServer::spawn(ServerConfig::default()).await;
+        let mut lines = BufReader::new(server.connect().await).lines();
+
+        // the request arrives in pieces, it's only answered once the
+        // newline does
+        for part in ["{\"method\":\"isPr", "ime\",\"number\":", "7}\n"] {
+            lines.get_mut().write_all(part.as_bytes()).await.unwrap();
+            lines.get_mut().flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+
+        drop(lines);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_tcp_several_requests_in_one_write() {
+        let server = TestServer::spawn(ServerConfig::default()).await;
+        let mut lines = BufReader::new(server.connect().await).lines();
+
+        lines
+            .get_mut()
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"ping\"}\n")
+            .await
+            .unwrap();
+
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"ping","ok":true}"#);
+
+        drop(lines);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_tcp_malformed_request_closes_socket() {
+        let server = TestServer::spawn(ServerConfig::default()).await;
+        let mut client = server.connect().await;
+
+        client.write_all(b"not json\n").await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "Invalid JSON\n");
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests_are_counted() {
+        let server = TestServer::spawn(ServerConfig {
+            max_line_bytes: 64,
+            stats_method: true,
+            ..Default::default()
+        })
+        .await;
+
+        // each malformed request closes its connection
+        let long_line = format!("{}\n", " ".repeat(100));
+        for request in [
+            "not json\n",
+            "{\"method\":\"isPrime\"\n",
+            "{\"method\":\"isComposite\",\"number\":7}\n",
+            &long_line,
+        ] {
+            let mut client = server.connect().await;
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, "Invalid JSON\n", "{request}");
+        }
+
+        let mut lines = BufReader::new(server.connect().await).lines();
+        lines
+            .get_mut()
+            .write_all(b"{\"method\":\"stats\"}\n")
+            .await
+            .unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+
+        assert_eq!(stats["malformed"], 4);
+        assert_eq!(
+            stats["malformed_reasons"],
+            serde_json::json!({ "invalid_json": 2, "line_too_long": 1, "invalid_request": 1 })
+        );
+
+        drop(lines);
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_tcp_client_disconnect_leaves_server_running() {
+        let server = TestServer::spawn(ServerConfig::default()).await;
+
+        // one client hangs up half way through a request
+        let mut client = server.connect().await;
+        client.write_all(b"{\"method\":").await.unwrap();
+        drop(client);
+
+        let mut lines = BufReader::new(server.connect().await).lines();
+        lines
+            .get_mut()
+            .write_all(b"{\"method\":\"ping\"}\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"ping","ok":true}"#);
+
+        drop(lines);
+        server.stop().await;
+    }
+
+    // run the connection handler over an in-memory pipe, returning the client
+    // end of the pipe and the handler's task
+    fn spawn_connection(
+        config: ServerConfig,
+    ) -> (DuplexStream, JoinHandle<Result<(), PrimeTimeError>>) {
+        let (client, server) = tokio::io::duplex(1024);
+        let handler = Arc::new(RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            ..config.clone()
+        }));
+        let peer = Some(IpAddr::from([127, 0, 0, 1]));
+        let task = tokio::spawn(
+            hanndle_connection(server, peer, Arc::new(config), handler)
+                .instrument(connection_span("127.0.0.1")),
+        );
+
+        (client, task)
+    }
+
+    #[tokio::test]
+    async fn test_connection_summary() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        let (mut client, task) = spawn_connection(ServerConfig::default());
+        client
+            .write_all(b"{\"method\":\"ping\"}\n{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0; 128];
+        let mut received = 0;
+        while received < 2 {
+            let n = client.read(&mut buf).await.unwrap();
+            received += buf[..n].iter().filter(|&&b| b == b'\n').count();
+        }
+        drop(client);
+        task.await.unwrap().unwrap();
+
+        let closed: serde_json::Value = captured
+            .output()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &serde_json::Value| line["fields"]["message"] == "Connection closed")
+            .unwrap();
+
+        let span = &closed["spans"][0];
+        assert_eq!(span["requests"], 2);
+        assert!(span["duration"].as_str().unwrap().ends_with('s'));
+    }
+
+    #[test]
+    fn test_parse_proxy_header() {
+        assert_eq!(
+            parse_proxy_header("PROXY TCP4 203.0.113.7 10.0.0.1 56324 8080\r\n").unwrap(),
+            Some("203.0.113.7:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_proxy_header("PROXY TCP6 2001:db8::7 2001:db8::1 56324 8080\r\n").unwrap(),
+            Some("[2001:db8::7]:56324".parse().unwrap())
+        );
+        assert_eq!(parse_proxy_header("PROXY UNKNOWN\r\n").unwrap(), None);
+        assert_eq!(
+            parse_proxy_header("PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").unwrap(),
+            None
+        );
+
+        for header in [
+            "PROXY TCP4 203.0.113.7 10.0.0.1 56324 8080\n",
+            "PROXY TCP4 203.0.113.7 10.0.0.1 56324\r\n",
+            "PROXY TCP4 2001:db8::7 10.0.0.1 56324 8080\r\n",
+            "PROXY TCP6 203.0.113.7 10.0.0.1 56324 8080\r\n",
+            "PROXY TCP4 203.0.113.7 10.0.0.1 65536 8080\r\n",
+            "PROXY TCP4 203.0.113.7 10.0.0.1 +80 8080\r\n",
+            "PROXY UDP4 203.0.113.7 10.0.0.1 56324 8080\r\n",
+            "PROXY  TCP4 203.0.113.7 10.0.0.1 56324 8080\r\n",
+            "{\"method\":\"isPrime\",\"number\":7}\r\n",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    parse_proxy_header(header),
+                    Err(PrimeTimeError::MalformedRequest(_))
+                ),
+                "{header:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        let (mut client, task) = spawn_connection(
+            ServerConfig::builder()
+                .proxy_protocol(true)
+                .build()
+                .unwrap(),
+        );
+        client
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 8080\r\n{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0; 64];
+        let len = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"{\"method\":\"isPrime\",\"prime\":true}\n");
+        drop(client);
+        task.await.unwrap().unwrap();
+
+        // the connection's logs show the client, not the balancer
+        let connected: serde_json::Value = captured
+            .output()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &serde_json::Value| line["fields"]["message"] == "Connected")
+            .unwrap();
+        assert_eq!(connected["spans"][0]["client"], "203.0.113.7:56324");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_malformed() {
+        for header in [
+            &b"PROXY TCP4 nonsense\r\n"[..],
+            b"{\"method\":\"isPrime\",\"number\":7}\n",
+            &[b'A'; 200],
+        ] {
+            let (mut client, task) = spawn_connection(
+                ServerConfig::builder()
+                    .proxy_protocol(true)
+                    .build()
+                    .unwrap(),
+            );
+            client.write_all(header).await.unwrap();
+            client
+                .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+                .await
+                .unwrap();
+
+            // closed without answering
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.is_empty(), "{:?}", String::from_utf8_lossy(header));
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_id() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        for _ in 0..2 {
+            let (mut client, task) = spawn_connection(ServerConfig::default());
+            client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+            let mut buf = [0; 64];
+            assert!(client.read(&mut buf).await.unwrap() > 0);
+            drop(client);
+            task.await.unwrap().unwrap();
+        }
+
+        // every line from a connection carries its id, and no two share one
+        let ids: Vec<u64> = captured
+            .output()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|line| line["spans"][0]["id"].as_u64().unwrap())
+            .collect();
+        let mut distinct = ids.clone();
+        distinct.dedup();
+
+        assert!(ids.len() > 2);
+        assert_eq!(distinct.len(), 2, "{ids:?}");
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_in_text_logs() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (client, task) = spawn_connection(ServerConfig::default());
+        drop(client);
+        task.await.unwrap().unwrap();
+
+        let output = captured.output();
+        assert!(!output.is_empty());
+        for line in output.lines() {
+            assert!(line.contains("Connection{id="), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_sampling() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        let (client, task) =
+            spawn_connection(ServerConfig::builder().log_sample_rate(3).build().unwrap());
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        // the malformed request is logged even though it isn't sampled
+        let requests = "{\"method\":\"ping\"}\n".repeat(4) + "not json\n";
+        writer.write_all(requests.as_bytes()).await.unwrap();
+        while lines.next_line().await.unwrap().is_some() {}
+        task.await.unwrap().unwrap();
+
+        let logged = |field: &str| {
+            captured
+                .output()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .filter_map(|line| line["fields"][field].as_str().map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+
+        // the first and fourth requests are sampled
+        assert_eq!(logged("received").len(), 2);
+        assert_eq!(logged("sending").len(), 3);
+        assert!(logged("sending")[2].contains("Invalid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_over_duplex() {
+        let (client, task) = spawn_connection(ServerConfig::default());
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":8}\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":false}"#);
+
+        // hanging up ends the connection cleanly
+        writer.shutdown().await.unwrap();
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handle_line_returns_buffer() {
+        let handler = Arc::new(RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            ..Default::default()
+        }));
+        let mut line = String::with_capacity(1024);
+        line.push_str("{\"method\":\"isPrime\",\"number\":7}\n");
+
+        let response = handle_line(&handler, &mut line, None).await.unwrap();
+
+        assert_eq!(response, "{\"method\":\"isPrime\",\"prime\":true}");
+        assert!(line.capacity() >= 1024);
+    }
+
+    #[tokio::test]
+    async fn test_crlf_line_endings() {
+        let (mut client, _) = spawn_connection(ServerConfig::default());
+
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0; 64];
+        let len = client.read(&mut buf).await.unwrap();
+
+        assert_eq!(&buf[..len], b"{\"method\":\"isPrime\",\"prime\":true}\n");
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let (client, _) = spawn_connection(ServerConfig::default());
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        // every request arrives at once, followed by half of another
+        let requests = "{\"method\":\"isPrime\",\"number\":7}\n".repeat(10);
+        writer.write_all(requests.as_bytes()).await.unwrap();
+        writer.write_all(b"{\"method\":\"isPr").await.unwrap();
+
+        // the answers are flushed without waiting for the rest of the line
+        for _ in 0..10 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+        }
+
+        writer.write_all(b"ime\",\"number\":8}\n").await.unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":false}"#);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_responses_keep_request_order() {
+        let (client, _) = spawn_connection(ServerConfig::default());
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        // the first request takes longest to answer
+        let requests = slow_request(1279)
+            + "{\"method\":\"isPrime\",\"number\":8}\n"
+            + "{\"method\":\"isPrime\",\"number\":7}\n";
+        writer.write_all(requests.as_bytes()).await.unwrap();
+
+        for prime in [true, false, true] {
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, format!(r#"{{"method":"isPrime","prime":{prime}}}"#));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_line_at_eof() {
+        for (drop_line, expected) in [(false, "Invalid JSON\n"), (true, "")] {
+            let (client, task) = spawn_connection(
+                ServerConfig::builder()
+                    .drop_unterminated_line(drop_line)
+                    .build()
+                    .unwrap(),
+            );
+            let (mut reader, mut writer) = tokio::io::split(client);
+
+            // the client hangs up before sending the end of the line
+            writer
+                .write_all(b"{\"method\":\"isPrime\",\"number\":7")
+                .await
+                .unwrap();
+            writer.shutdown().await.unwrap();
+
+            let mut response = String::new();
+            reader.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, expected, "drop_unterminated_line = {drop_line}");
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_complete_request_is_not_answered() {
+        let (client, _) = spawn_connection(ServerConfig::default());
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        // valid JSON, but without the newline it isn't a finished request
+        writer.write_all(b"{\"method\":\"ping\"}").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut response = String::new();
+        reader.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "Invalid JSON\n");
+    }
+
+    // a checker slow enough for the client to give up on it
+    #[derive(Debug)]
+    struct SlowChecker;
+
+    impl crate::PrimalityChecker for SlowChecker {
+        fn is_prime(&self, _: &BigInt) -> bool {
+            std::thread::sleep(Duration::from_secs(3));
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_abandons_request() {
+        let (mut client, task) = spawn_connection(
+            ServerConfig::builder()
+                .primality(SlowChecker)
+                .cancel_on_disconnect(true)
+                .build()
+                .unwrap(),
+        );
+
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":1000003}\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(client);
+
+        // the connection ends without waiting for the check to finish
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("connection released before the check finished")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_requests_per_connection() {
+        let (client, task) = spawn_connection(
+            ServerConfig::builder()
+                .max_requests_per_connection(2)
+                .build()
+                .unwrap(),
+        );
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        for _ in 0..2 {
+            writer.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, r#"{"method":"ping","ok":true}"#);
+        }
+
+        // the third request closes the connection without an answer
+        writer.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap(), None);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_throttles_burst() {
+        let (client, _) = spawn_connection(ServerConfig {
+            rate_limit: Some(RateLimit {
+                requests_per_second: 5.0,
+                burst: 2,
+            }),
+            ..Default::default()
+        });
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        let start = std::time::Instant::now();
+
+        for _ in 0..4 {
+            writer
+                .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+                .await
+                .unwrap();
+        }
+        for _ in 0..4 {
+            lines.next_line().await.unwrap().unwrap();
+        }
+
+        // two requests come out of the burst, the other two wait 200ms each
+        assert!(start.elapsed() >= Duration::from_millis(350));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (mut client, _) = spawn_connection(ServerConfig {
+            idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+
+        let request = b"{\"method\":\"isPrime\",\"number\":7}\n";
+        let mut buf = [0; 64];
+
+        // requests sent within the timeout keep the connection alive
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(request).await.unwrap();
+            assert!(client.read(&mut buf).await.unwrap() > 0);
+        }
+
+        // going quiet gets the connection closed
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_first_request_timeout() {
+        let (client, task) = spawn_connection(
+            ServerConfig::builder()
+                .first_request_timeout(Duration::from_millis(200))
+                .build()
+                .unwrap(),
+        );
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        // sent a byte at a time, the line takes far longer to arrive than
+        // the client is given
+        let dribble = tokio::spawn(async move {
+            for byte in b"{\"method\":\"ping\"}\n" {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if writer.write_all(&[*byte]).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let start = std::time::Instant::now();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).await.unwrap();
+
+        assert!(output.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        task.await.unwrap().unwrap();
+        dribble.abort();
+    }
+
+    #[tokio::test]
+    async fn test_first_request_timeout_only_applies_to_first() {
+        let (mut client, _) = spawn_connection(
+            ServerConfig::builder()
+                .first_request_timeout(Duration::from_millis(100))
+                .build()
+                .unwrap(),
+        );
+        let mut buf = [0; 64];
+
+        client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+        assert!(client.read(&mut buf).await.unwrap() > 0);
+
+        // later requests only have to beat the idle timeout
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.write_all(b"{\"method\":\"ping\"}\n").await.unwrap();
+        assert!(client.read(&mut buf).await.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_write_errors_from_departed_clients_are_quiet() {
+        use std::io::{Error, ErrorKind};
+
+        let level = |kind: ErrorKind| {
+            let captured = Captured::default();
+            tracing::subscriber::with_default(captured.json_subscriber(), || {
+                log_write_error(&Error::from(kind))
+            });
+
+            let line: serde_json::Value =
+                serde_json::from_str(captured.output().trim_end()).unwrap();
+            line["level"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(level(ErrorKind::BrokenPipe), "DEBUG");
+        assert_eq!(level(ErrorKind::ConnectionReset), "DEBUG");
+        assert_eq!(level(ErrorKind::NotConnected), "DEBUG");
+        assert_eq!(level(ErrorKind::TimedOut), "INFO");
+        assert_eq!(level(ErrorKind::PermissionDenied), "ERROR");
+    }
+
+    #[tokio::test]
+    async fn test_half_closed_client_is_answered() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        // with the default config
+        let (mut client, task) = spawn_connection(ServerConfig::default());
+
+        // the client is done sending but still reading
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"ping\"}\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        assert_eq!(
+            output,
+            "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"ping\",\"ok\":true}\n"
+        );
+        task.await.unwrap().unwrap();
+
+        assert!(!captured.output().contains("\"ERROR\""));
+    }
+
+    // a client that sent some requests and then shut down its reading side,
+    // so nothing can be written back to it
+    struct DeafClient(std::io::Cursor<&'static [u8]>);
+
+    impl AsyncRead for DeafClient {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            // it keeps the connection open once it has sent everything
+            if self.0.position() < self.0.get_ref().len() as u64 {
+                Pin::new(&mut self.0).poll_read(cx, buf)
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncWrite for DeafClient {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_client_is_closed_quietly() {
+        let captured = Captured::default();
+        let _guard = tracing::subscriber::set_default(captured.json_subscriber());
+
+        let config = ServerConfig::default();
+        let handler = Arc::new(RequestHandler::new(&ServerConfig {
+            sieve_limit: 1000,
+            ..config.clone()
+        }));
+        let client = DeafClient(std::io::Cursor::new(
+            b"{\"method\":\"ping\"}\n{\"method\":\"ping\"}\n{\"method\":\"ping\"}\n",
+        ));
+
+        // the connection ends once writing fails, without waiting on more
+        // requests from a client that can't be answered
+        timeout(
+            Duration::from_secs(5),
+            hanndle_connection(client, None, Arc::new(config), handler),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let output = captured.output();
+        assert_eq!(
+            output
+                .matches("Client went away before reading its responses")
+                .count(),
+            1
+        );
+        assert!(!output.contains("\"ERROR\""));
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let (client, task) = spawn_connection(
+            ServerConfig::builder()
+                .write_timeout(Duration::from_millis(100))
+                .build()
+                .unwrap(),
+        );
+        let (_reader, mut writer) = tokio::io::split(client);
+
+        // far more responses than fit in the pipe, which nobody reads
+        tokio::spawn(async move {
+            let requests = "{\"method\":\"ping\"}\n".repeat(1000);
+            let _ = writer.write_all(requests.as_bytes()).await;
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("connection closes once writes time out")
+            .unwrap()
+            .unwrap();
+    }
+
+    // a request for the Mersenne prime 2^exponent - 1, these take a while to
+    // check
+    fn slow_request(exponent: usize) -> String {
+        let n = (BigInt::from(1) << exponent) - 1;
+        format!("{{\"method\":\"isPrime\",\"number\":{n}}}\n")
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_does_not_block_others() {
+        let (slow, _) = spawn_connection(ServerConfig::default());
+        let (fast, _) = spawn_connection(ServerConfig::default());
+
+        let (slow_reader, mut slow_writer) = tokio::io::split(slow);
+        let (fast_reader, mut fast_writer) = tokio::io::split(fast);
+        let mut slow_lines = BufReader::new(slow_reader).lines();
+        let mut fast_lines = BufReader::new(fast_reader).lines();
+
+        slow_writer
+            .write_all(slow_request(1279).as_bytes())
+            .await
+            .unwrap();
+        fast_writer
+            .write_all(b"{\"method\":\"ping\"}\n")
+            .await
+            .unwrap();
+
+        // the test runtime has a single worker thread, so the ping is only
+        // answered before the slow check finishes if that isn't running on it
+        fast_lines.next_line().await.unwrap().unwrap();
+        let pending = timeout(Duration::ZERO, slow_lines.next_line()).await;
+        assert!(pending.is_err());
+
+        let line = slow_lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // the slow check carries on after timing out, leaving a second
+        // worker for the next request
+        let (client, _) = spawn_connection(ServerConfig {
+            request_timeout: Some(Duration::from_millis(1)),
+            compute_workers: 2,
+            ..Default::default()
+        });
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(slow_request(1279).as_bytes())
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, "Invalid JSON");
+
+        // the connection carries on with the next request
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_request_frees_its_worker() {
+        let (client, _) = spawn_connection(ServerConfig {
+            request_timeout: Some(Duration::from_secs(1)),
+            compute_workers: 1,
+            max_next_prime_digits: 1000,
+            ..Default::default()
+        });
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        // the next prime after the 1000 digit prime 10^999 + 7 is 656
+        // further on, far more checks than fit in the timeout
+        let request = format!(
+            "{{\"method\":\"nextPrime\",\"number\":1{}7}}\n",
+            "0".repeat(998)
+        );
+        for _ in 0..2 {
+            writer.write_all(request.as_bytes()).await.unwrap();
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, "Invalid JSON");
+        }
+
+        // the searches gave up, so the only worker is free again
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let line = timeout(Duration::from_secs(10), lines.next_line())
+            .await
+            .expect("a worker is free")
+            .unwrap()
+            .unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_close_on_request_timeout() {
+        let (mut client, task) = spawn_connection(ServerConfig {
+            request_timeout: Some(Duration::from_millis(1)),
+            close_on_request_timeout: true,
+            ..Default::default()
+        });
+
+        client
+            .write_all(slow_request(1279).as_bytes())
+            .await
+            .unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        assert_eq!(output, "Invalid JSON\n");
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_structured_errors() {
+        let (mut client, _) = spawn_connection(ServerConfig {
+            structured_errors: true,
+            ..Default::default()
+        });
+
+        client
+            .write_all(b"{\"method\":\"isPrime\"}\n")
+            .await
+            .unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        let response: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(response["error"], "invalid_request");
+        assert!(response["detail"].as_str().unwrap().contains("number"));
+    }
+
+    #[tokio::test]
+    async fn test_pretty_responses_are_rejected() {
+        // indented responses can't be read one per line
+        let config = ServerConfig::builder()
+            .addr("127.0.0.1:0".parse().unwrap())
+            .pretty_responses(true)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            start(config),
+            Err(PrimeTimeError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_line_too_long() {
+        let (mut client, _) = spawn_connection(ServerConfig {
+            max_line_bytes: 16,
+            ..Default::default()
+        });
+
+        client.write_all(&[b'1'; 32]).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        assert_eq!(output, "Invalid JSON\n");
+    }
+
+    #[tokio::test]
+    async fn test_line_length_limit_boundary() {
+        let request = "{\"method\":\"isPrime\",\"number\":7}\n";
+
+        // the limit counts the newline, so a line exactly at it is answered
+        // and one byte less is not
+        for (max_line_bytes, expected) in [
+            (request.len(), "{\"method\":\"isPrime\",\"prime\":true}\n"),
+            (request.len() - 1, "Invalid JSON\n"),
+        ] {
+            let (client, _) = spawn_connection(ServerConfig {
+                max_line_bytes,
+                ..Default::default()
+            });
+            let (reader, mut writer) = tokio::io::split(client);
+            let mut reader = BufReader::new(reader);
+
+            writer.write_all(request.as_bytes()).await.unwrap();
+
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            assert_eq!(line, expected, "max_line_bytes = {max_line_bytes}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_line_too_long_after_good_request() {
+        let (mut client, task) = spawn_connection(ServerConfig {
+            max_line_bytes: 64,
+            ..Default::default()
+        });
+
+        // the good request is answered before the long line closes the
+        // connection
+        let mut requests = b"{\"method\":\"ping\"}\n".to_vec();
+        requests.extend_from_slice(&[b' '; 128]);
+        requests.push(b'\n');
+        client.write_all(&requests).await.unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        assert_eq!(output, "{\"method\":\"ping\",\"ok\":true}\nInvalid JSON\n");
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let (mut client, task) = spawn_connection(ServerConfig::default());
+
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":\xff\xfe}\n")
+            .await
+            .unwrap();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        assert_eq!(output, "Invalid JSON\n");
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_after_malformed() {
+        let (mut client, task) = spawn_connection(ServerConfig::default());
+
+        client
+            .write_all(b"not json\n{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+
+        // the server closes the connection, so this reads until EOF
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+
+        assert_eq!(output, "Invalid JSON\n");
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    // a request in the length-prefixed framing
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // read one length-prefixed response
+    async fn read_frame_response(reader: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut prefix = [0; 4];
+        reader.read_exact(&mut prefix).await.unwrap();
+
+        let mut payload = vec![0; u32::from_be_bytes(prefix) as usize];
+        reader.read_exact(&mut payload).await.unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    fn length_prefixed() -> ServerConfig {
+        ServerConfig::builder()
+            .framing(FramingMode::LengthPrefixed)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_round_trip() {
+        let (mut client, task) = spawn_connection(length_prefixed());
+
+        // a newline inside a frame is just whitespace
+        client
+            .write_all(&frame(b"{\"method\":\"isPrime\",\n\"number\":7}"))
+            .await
+            .unwrap();
+        let response = read_frame_response(&mut client).await;
+        assert_eq!(response, r#"{"method":"isPrime","prime":true}"#);
+
+        client.shutdown().await.unwrap();
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_pipelined() {
+        let (mut client, _) = spawn_connection(length_prefixed());
+
+        // both frames and the start of a third arrive in one write
+        let mut requests = frame(b"{\"method\":\"isPrime\",\"number\":8}");
+        requests.extend(frame(b"{\"method\":\"isPrime\",\"number\":7}"));
+        requests.extend_from_slice(&[0, 0]);
+        client.write_all(&requests).await.unwrap();
+
+        for prime in [false, true] {
+            let response = read_frame_response(&mut client).await;
+            assert_eq!(
+                response,
+                format!(r#"{{"method":"isPrime","prime":{prime}}}"#)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_frame_too_long() {
+        let (mut client, task) = spawn_connection(ServerConfig {
+            max_line_bytes: 16,
+            ..length_prefixed()
+        });
+
+        // only the prefix is sent, the server doesn't wait for the rest
+        client.write_all(&32u32.to_be_bytes()).await.unwrap();
+
+        assert_eq!(read_frame_response(&mut client).await, "Invalid JSON");
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_truncated_frame() {
+        // cut off in the prefix and in the payload
+        for truncated in [&frame(b"{}")[..2], &frame(b"{\"method\":\"ping\"}")[..10]] {
+            let (client, task) = spawn_connection(length_prefixed());
+            let (mut reader, mut writer) = tokio::io::split(client);
+
+            writer.write_all(truncated).await.unwrap();
+            writer.shutdown().await.unwrap();
+
+            assert_eq!(read_frame_response(&mut reader).await, "Invalid JSON");
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    // read responses up to and including `delimiter`
+    async fn read_until_delimiter(
+        reader: &mut (impl AsyncBufRead + Unpin),
+        delimiter: u8,
+    ) -> String {
+        let mut response = Vec::new();
+        reader.read_until(delimiter, &mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_nul_delimiter() {
+        let (client, task) =
+            spawn_connection(ServerConfig::builder().delimiter(0).build().unwrap());
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+
+        // a newline is only whitespace, and pipelined requests are still
+        // split on the delimiter
+        writer
+            .write_all(
+                b"{\"method\":\"isPrime\",\n\"number\":7}\0{\"method\":\"isPrime\",\"number\":8}\0",
+            )
+            .await
+            .unwrap();
+
+        for prime in [true, false] {
+            assert_eq!(
+                read_until_delimiter(&mut reader, 0).await,
+                format!("{{\"method\":\"isPrime\",\"prime\":{prime}}}\0")
+            );
+        }
+
+        // malformed responses are terminated the same way
+        writer.write_all(b"{}\n\0").await.unwrap();
+        assert_eq!(read_until_delimiter(&mut reader, 0).await, "Invalid JSON\0");
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_nul_delimiter_unterminated() {
+        let (mut client, task) =
+            spawn_connection(ServerConfig::builder().delimiter(0).build().unwrap());
+
+        // a newline doesn't end the request
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "Invalid JSON\0");
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_request_buffered() {
+        let request = frame(b"{}");
+        let framed = ServerConfig::builder()
+            .framing(FramingMode::LengthPrefixed)
+            .build()
+            .unwrap();
+
+        assert!(request_buffered(&request, &framed));
+        assert!(!request_buffered(&request[..5], &framed));
+        assert!(!request_buffered(&request[..3], &framed));
+
+        let lines = ServerConfig::default();
+        assert!(request_buffered(b"{}\n", &lines));
+        assert!(!request_buffered(b"{}", &lines));
+
+        let nul = ServerConfig::builder().delimiter(0).build().unwrap();
+        assert!(request_buffered(b"{}\0", &nul));
+        assert!(!request_buffered(b"{}\n", &nul));
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use num_bigint::{BigInt, Sign};
+
+/// A precomputed Sieve of Eratosthenes for answering small numbers without
+/// running the probabilistic test.
+///
+/// Cloning is cheap, the table is shared.
+#[derive(Clone, Debug)]
+pub struct Sieve {
+    primes: Arc<[bool]>,
+}
+
+impl Sieve {
+    /// Sieve every number below `limit`
+    pub fn new(limit: usize) -> Self {
+        let mut primes = vec![true; limit];
+
+        // 0 and 1 are not prime
+        for n in primes.iter_mut().take(2) {
+            *n = false;
+        }
+
+        let mut i = 2;
+        while i * i < limit {
+            if primes[i] {
+                for multiple in (i * i..limit).step_by(i) {
+                    primes[multiple] = false;
+                }
+            }
+            i += 1;
+        }
+
+        Self {
+            primes: primes.into(),
+        }
+    }
+
+    /// The first number the sieve can't answer
+    pub fn limit(&self) -> usize {
+        self.primes.len()
+    }
+
+    /// The primes below the limit, in ascending order
+    pub fn primes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.primes
+            .iter()
+            .enumerate()
+            .filter_map(|(n, &prime)| prime.then_some(n))
+    }
+
+    /// Number of primes strictly below `n`, or `None` if `n` is above the
+    /// sieve's limit
+    pub fn count_below(&self, n: usize) -> Option<usize> {
+        let primes = self.primes.get(..n)?;
+        Some(primes.iter().filter(|&&prime| prime).count())
+    }
+
+    /// Whether `n` is prime, or `None` if it is above the sieve's limit.
+    /// Negative numbers are never prime.
+    pub fn lookup(&self, n: &BigInt) -> Option<bool> {
+        if n.sign() == Sign::Minus {
+            return Some(false);
+        }
+
+        let n = usize::try_from(n).ok()?;
+        self.primes.get(n).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::is_number_prime;
+
+    #[test]
+    fn test_sieve_small_numbers() {
+        let sieve = Sieve::new(100);
+        let primes: Vec<usize> = (0..100)
+            .filter(|&n| sieve.lookup(&BigInt::from(n)).unwrap())
+            .collect();
+
+        assert_eq!(primes.len(), 25);
+        assert_eq!(primes[..5], [2, 3, 5, 7, 11]);
+    }
+
+    #[test]
+    fn test_count_below() {
+        let sieve = Sieve::new(100);
+
+        assert_eq!(sieve.count_below(0), Some(0));
+        assert_eq!(sieve.count_below(3), Some(1));
+        assert_eq!(sieve.count_below(100), Some(25));
+        assert_eq!(sieve.count_below(101), None);
+    }
+
+    #[test]
+    fn test_sieve_negative() {
+        let sieve = Sieve::new(100);
+
+        assert_eq!(sieve.lookup(&BigInt::from(-7)), Some(false));
+    }
+
+    #[test]
+    fn test_sieve_above_limit() {
+        let sieve = Sieve::new(100);
+
+        assert_eq!(sieve.lookup(&BigInt::from(99)), Some(false));
+        assert_eq!(sieve.lookup(&BigInt::from(100)), None);
+    }
+
+    #[test]
+    fn test_sieve_agrees_with_fallback_at_limit() {
+        // 1009 and 1013 are primes on either side of the limit
+        let sieve = Sieve::new(1010);
+
+        for n in 950..1010 {
+            let n = BigInt::from(n);
+            assert_eq!(sieve.lookup(&n), Some(is_number_prime(&n)), "{n}");
+        }
+        for n in 1010..1050 {
+            assert_eq!(sieve.lookup(&BigInt::from(n)), None);
+        }
+    }
+}
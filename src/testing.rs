@@ -0,0 +1,100 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use crate::{server::serve_until, PrimeTimeError, ServerConfig};
+
+// A writer keeping everything written to it, for tests that check logs
+#[derive(Clone, Default)]
+pub(crate) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    // Everything written so far
+    pub(crate) fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+
+    // A subscriber writing JSON logs at every level to this writer
+    pub(crate) fn json_subscriber(&self) -> impl tracing::Subscriber + Send + Sync {
+        let writer = self.clone();
+
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .fmt_fields(crate::logging::JsonFields)
+            .event_format(crate::logging::JsonFormat)
+            .with_writer(move || writer.clone())
+            .finish()
+    }
+}
+
+impl std::io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// A real server on an ephemeral port, for tests that go through TCP sockets
+// rather than calling the handler directly
+pub(crate) struct TestServer {
+    pub(crate) addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), PrimeTimeError>>,
+}
+
+impl TestServer {
+    // Start serving on 127.0.0.1 with a small sieve so startup is quick. The
+    // server runs until shut down, whatever its clients do.
+    pub(crate) async fn spawn(config: ServerConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, signal) = oneshot::channel();
+
+        let config = ServerConfig {
+            sieve_limit: 1000,
+            ..config
+        };
+        let task = tokio::spawn(serve_until(listener, config, async {
+            let _ = signal.await;
+            Ok(())
+        }));
+
+        Self {
+            addr,
+            shutdown,
+            task,
+        }
+    }
+
+    pub(crate) async fn connect(&self) -> TcpStream {
+        TcpStream::connect(self.addr).await.unwrap()
+    }
+
+    // Ask the server to stop, returning its task to wait on. Connections in
+    // flight get the configured grace period to finish.
+    pub(crate) fn shutdown(self) -> JoinHandle<Result<(), PrimeTimeError>> {
+        let _ = self.shutdown.send(());
+        self.task
+    }
+
+    // Stop the server and wait for it, failing the test if that takes more
+    // than a few seconds
+    pub(crate) async fn stop(self) {
+        tokio::time::timeout(Duration::from_secs(5), self.shutdown())
+            .await
+            .expect("server stops in time")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use serde_json::{Map, Number, Value};
+
+// A parser for the subset of TOML used by config files: `[table]` headers
+// and `key = value` pairs whose values are strings, integers, floats or
+// booleans. The document is turned into a JSON value so it can be
+// deserialized with serde.
+//
+// Errors name the line they were found on.
+pub(crate) fn parse(input: &str) -> Result<Value, String> {
+    let mut root = Map::new();
+    // the table keys are currently being added to, None for the top level
+    let mut table: Option<String> = None;
+
+    for (i, line) in input.lines().enumerate() {
+        let line_number = i + 1;
+        let line = strip_comment(line).trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .map(str::trim)
+                .filter(|name| is_bare_key(name))
+                .ok_or_else(|| format!("line {line_number}: invalid table header"))?;
+
+            if root.contains_key(name) {
+                return Err(format!("line {line_number}: duplicate table `{name}`"));
+            }
+
+            root.insert(name.to_string(), Value::Object(Map::new()));
+            table = Some(name.to_string());
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {line_number}: expected `key = value`"))?;
+        let key = key.trim();
+
+        if !is_bare_key(key) {
+            return Err(format!("line {line_number}: invalid key `{key}`"));
+        }
+
+        let value = parse_value(value.trim()).map_err(|e| format!("line {line_number}: {e}"))?;
+
+        let map = match &table {
+            Some(name) => match root.get_mut(name) {
+                Some(Value::Object(map)) => map,
+                _ => unreachable!("tables are inserted when their header is read"),
+            },
+            None => &mut root,
+        };
+
+        if map.insert(key.to_string(), value).is_some() {
+            return Err(format!("line {line_number}: duplicate key `{key}`"));
+        }
+    }
+
+    Ok(Value::Object(root))
+}
+
+// Drop a trailing `# comment`, leaving any `#` inside a string alone
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => (),
+        }
+        escaped = false;
+    }
+
+    line
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(s) = value.strip_prefix('"') {
+        return parse_basic_string(s).map(Value::String);
+    }
+
+    if let Some(s) = value.strip_prefix('\'') {
+        return s
+            .strip_suffix('\'')
+            .filter(|s| !s.contains('\''))
+            .map(|s| Value::String(s.to_string()))
+            .ok_or_else(|| "unterminated string".to_string());
+    }
+
+    match value {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        "" => return Err("missing value".to_string()),
+        _ => (),
+    }
+
+    // underscores may separate digits, like 1_000_000
+    let digits = value.replace('_', "");
+
+    if let Ok(n) = digits.parse::<i64>() {
+        return Ok(Value::Number(n.into()));
+    }
+
+    if let Some(n) = digits.parse::<f64>().ok().and_then(Number::from_f64) {
+        return Ok(Value::Number(n));
+    }
+
+    Err(format!("unsupported value `{value}`"))
+}
+
+// Parse the rest of a double quoted string, after the opening quote
+fn parse_basic_string(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().is_empty() => return Ok(out),
+            '"' => return Err("unexpected characters after string".to_string()),
+            '\\' => match chars.next() {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => return Err(format!("unsupported escape `\\{c}`")),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let input = r#"
+            # a comment
+            addr = "0.0.0.0:9000" # trailing comment
+            cache_size = 1_000
+            idle_timeout = 2.5
+            dual_stack = false
+
+            [rate_limit]
+            burst = 20
+            label = 'raw # not a comment'
+        "#;
+
+        let expected = json!({
+            "addr": "0.0.0.0:9000",
+            "cache_size": 1000,
+            "idle_timeout": 2.5,
+            "dual_stack": false,
+            "rate_limit": { "burst": 20, "label": "raw # not a comment" },
+        });
+
+        assert_eq!(parse(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        let cases = [
+            ("addr = \"unterminated", "line 1: unterminated string"),
+            ("a = 1\na = 2", "line 2: duplicate key `a`"),
+            ("\n\njust words", "line 3: expected `key = value`"),
+            ("[table", "line 1: invalid table header"),
+            ("a = [1, 2]", "line 1: unsupported value `[1, 2]`"),
+        ];
+
+        for (input, error) in cases {
+            assert_eq!(parse(input).unwrap_err(), error, "{input}");
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    net::UdpSocket,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{
+    server::{clean_up_rate_limiter, handle_line, shutdown_signal},
+    PrimeTimeError, RequestHandler, ServerConfig,
+};
+
+// Datagrams can't be larger than this over IPv4 or IPv6 without jumbograms
+const MAX_DATAGRAM_BYTES: usize = 65_536;
+
+// Without a rate limit, how many times longer than its request an answer can
+// be. Enough for any ordinary answer, including the malformed response to
+// all but the shortest datagrams.
+const MAX_AMPLIFICATION: usize = 4;
+
+/// Start a UDP server on `socket`. Each datagram holds one request and is
+/// answered with one datagram sent back to its sender.
+///
+/// The sender of a datagram can be forged, so the server takes care not to
+/// flood whoever it claims to be. At most
+/// [`ServerConfig::max_connections`] datagrams are answered at once, and
+/// datagrams over [`ServerConfig::rate_limit`] are dropped. Without a rate
+/// limit, answers more than four times longer than their request are dropped
+/// too.
+pub async fn run_udp(socket: SocketAddr) -> Result<(), PrimeTimeError> {
+    run_udp_with_config(ServerConfig::builder().addr(socket).build()?).await
+}
+
+/// Like [`run_udp`], but with the given configuration. Settings that only
+/// make sense for connections, like the idle timeout, are ignored, and
+/// `max_connections` limits the datagrams being answered at once.
+pub async fn run_udp_with_config(config: ServerConfig) -> Result<(), PrimeTimeError> {
+    config.validate_lines()?;
+    let socket = UdpSocket::bind(config.addr).await?;
+    tracing::info!("Listening on udp://{}", socket.local_addr()?);
+
+    serve_udp(socket, config).await
+}
+
+/// Answer datagrams on `socket` until SIGINT or SIGTERM is received
+pub async fn serve_udp(socket: UdpSocket, config: ServerConfig) -> Result<(), PrimeTimeError> {
+    serve_udp_until(socket, config, shutdown_signal()).await
+}
+
+// Answer datagrams until the shutdown future resolves
+async fn serve_udp_until(
+    socket: UdpSocket,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+) -> Result<(), PrimeTimeError> {
+    config.validate_lines()?;
+
+    let socket = Arc::new(socket);
+    let handler = Arc::new(RequestHandler::new(&config));
+    let mut buf = vec![0; MAX_DATAGRAM_BYTES];
+
+    // each datagram being answered holds a permit, while none are left the
+    // socket isn't read and the kernel drops what doesn't fit its buffer
+    let semaphore = Arc::new(Semaphore::new(config.max_connections));
+    clean_up_rate_limiter(&handler);
+
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            (permit, received) = receive(&socket, &semaphore, &mut buf) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    // on some platforms an ICMP error from an earlier reply
+                    // shows up here, it only concerns that one client
+                    Err(e) => {
+                        tracing::warn!("Failed to receive datagram: {}", e);
+                        continue;
+                    }
+                };
+
+                if let Some(limiter) = handler.rate_limiter() {
+                    if !limiter.try_acquire(peer.ip()) {
+                        tracing::debug!(client = %peer, "Rate limited, dropping datagram");
+                        continue;
+                    }
+                }
+
+                let mut datagram = String::from_utf8_lossy(&buf[..len]).into_owned();
+                tracing::info!(client = %peer, received = ?datagram.trim_end_matches(['\r', '\n', char::from(config.delimiter)]));
+
+                let socket = socket.clone();
+                let handler = handler.clone();
+                let request_timeout = config.request_timeout;
+                let delimiter = char::from(config.delimiter);
+
+                tokio::spawn(async move {
+                    let _permit = permit;
+
+                    let mut response = match handle_line(&handler, &mut datagram, request_timeout).await {
+                        Ok(response) => response,
+                        Err(e) => {
+                            tracing::info!(client = %peer, "Bad request: {}", e);
+                            handler.metrics().record_malformed(&e);
+                            handler.error_line(&e)
+                        }
+                    };
+                    // terminated like a line on a connection, though the
+                    // datagram already delimits it
+                    response.push(delimiter);
+
+                    // a forged sender could otherwise have the server send
+                    // its victim far more than it was sent
+                    if handler.rate_limiter().is_none() && response.len() > len * MAX_AMPLIFICATION {
+                        tracing::debug!(client = %peer, "Answer too long for the request, dropping it");
+                        return;
+                    }
+
+                    if let Err(e) = socket.send_to(response.as_bytes(), peer).await {
+                        tracing::warn!(client = %peer, "Failed to send response: {}", e);
+                    }
+                });
+            }
+            signal = &mut shutdown => {
+                signal?;
+
+                tracing::info!("Shutting down");
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Wait for a permit to answer a datagram, then receive one
+async fn receive(
+    socket: &UdpSocket,
+    semaphore: &Arc<Semaphore>,
+    buf: &mut [u8],
+) -> (OwnedSemaphorePermit, std::io::Result<(usize, SocketAddr)>) {
+    let permit = semaphore
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("the semaphore is never closed");
+
+    (permit, socket.recv_from(buf).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use num_bigint::BigInt;
+
+    use crate::RateLimit;
+
+    // a limit the tests never reach, so answers of any size are sent
+    const GENEROUS: RateLimit = RateLimit {
+        requests_per_second: 1000.0,
+        burst: 1000,
+    };
+
+    // start a server on an ephemeral port and a client to talk to it
+    async fn udp_pair(config: ServerConfig) -> UdpSocket {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let config = ServerConfig {
+            sieve_limit: 1000,
+            ..config
+        };
+        tokio::spawn(serve_udp_until(server, config, std::future::pending()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        client
+    }
+
+    async fn exchange(client: &UdpSocket, request: &str) -> String {
+        let mut buf = [0; 1024];
+
+        client.send(request.as_bytes()).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    // whether `client` receives anything within a short wait
+    async fn answered(client: &UdpSocket) -> bool {
+        let mut buf = [0; 1024];
+
+        tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_udp_request() {
+        let client = udp_pair(ServerConfig {
+            rate_limit: Some(GENEROUS),
+            ..Default::default()
+        })
+        .await;
+
+        let response = exchange(&client, r#"{"method":"isPrime","number":7}"#).await;
+        assert_eq!(response, "{\"method\":\"isPrime\",\"prime\":true}\n");
+
+        let response = exchange(&client, r#"{"method":"isPrime","number":8}"#).await;
+        assert_eq!(response, "{\"method\":\"isPrime\",\"prime\":false}\n");
+    }
+
+    #[tokio::test]
+    async fn test_udp_malformed() {
+        let client = udp_pair(ServerConfig {
+            rate_limit: Some(GENEROUS),
+            ..Default::default()
+        })
+        .await;
+
+        assert_eq!(exchange(&client, "not json").await, "Invalid JSON\n");
+
+        // there's no connection to close, later requests are still answered
+        let response = exchange(&client, r#"{"method":"ping"}"#).await;
+        assert_eq!(response, "{\"method\":\"ping\",\"ok\":true}\n");
+    }
+
+    #[tokio::test]
+    async fn test_udp_rejects_pretty_responses() {
+        let config = ServerConfig::builder()
+            .addr("127.0.0.1:0".parse().unwrap())
+            .pretty_responses(true)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            run_udp_with_config(config).await,
+            Err(PrimeTimeError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_udp_default_config_answers() {
+        let client = udp_pair(ServerConfig::default()).await;
+
+        // both answers are a little longer than their requests
+        let response = exchange(&client, r#"{"method":"isPrime","number":7}"#).await;
+        assert_eq!(response, "{\"method\":\"isPrime\",\"prime\":true}\n");
+
+        assert_eq!(exchange(&client, "not json").await, "Invalid JSON\n");
+    }
+
+    #[tokio::test]
+    async fn test_udp_drops_amplifying_answers() {
+        let client = udp_pair(ServerConfig::default()).await;
+
+        // the malformed response is thirteen times longer
+        client.send(b"x").await.unwrap();
+        assert!(!answered(&client).await);
+
+        // later requests are still answered
+        let response = exchange(&client, r#"{"method":"ping"}"#).await;
+        assert_eq!(response, "{\"method\":\"ping\",\"ok\":true}\n");
+    }
+
+    #[tokio::test]
+    async fn test_udp_rate_limit_drops_datagrams() {
+        let client = udp_pair(ServerConfig {
+            rate_limit: Some(RateLimit {
+                requests_per_second: 0.1,
+                burst: 2,
+            }),
+            ..Default::default()
+        })
+        .await;
+
+        for _ in 0..2 {
+            let response = exchange(&client, r#"{"method":"ping"}"#).await;
+            assert_eq!(response, "{\"method\":\"ping\",\"ok\":true}\n");
+        }
+
+        client.send(br#"{"method":"ping"}"#).await.unwrap();
+        assert!(!answered(&client).await);
+    }
+
+    #[tokio::test]
+    async fn test_udp_datagrams_in_flight_are_bounded() {
+        let client = udp_pair(ServerConfig {
+            max_connections: 1,
+            compute_workers: 2,
+            rate_limit: Some(GENEROUS),
+            ..Default::default()
+        })
+        .await;
+
+        // the Mersenne prime 2^1279 - 1 takes a while to check
+        let n = (BigInt::from(1) << 1279) - 1;
+        let slow = format!(r#"{{"method":"isPrime","number":{n}}}"#);
+        client.send(slow.as_bytes()).await.unwrap();
+        client.send(br#"{"method":"ping"}"#).await.unwrap();
+
+        // with a single permit the ping waits for the slow answer, even
+        // though a worker is free
+        let mut buf = vec![0; 1024];
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"{\"method\":\"isPrime\",\"prime\":true}\n");
+
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"{\"method\":\"ping\",\"ok\":true}\n");
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{
+    server::{serve_until, shutdown_signal, Listener},
+    PrimeTimeError, ServerConfig,
+};
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> std::io::Result<(UnixStream, Option<SocketAddr>)> {
+        let (stream, _) = UnixListener::accept(self).await?;
+        Ok((stream, None))
+    }
+}
+
+/// Start the server on a Unix domain socket at `path`, for clients on the
+/// same machine
+pub async fn run_unix(path: impl AsRef<Path>) -> Result<(), PrimeTimeError> {
+    run_unix_with_config(path, ServerConfig::default()).await
+}
+
+/// Like [`run_unix`], but with the given configuration. `config.addr` and the
+/// TCP socket options are ignored, and so is rate limiting since clients
+/// don't have an IP address.
+pub async fn run_unix_with_config(
+    path: impl AsRef<Path>,
+    config: ServerConfig,
+) -> Result<(), PrimeTimeError> {
+    serve_unix_until(path.as_ref().to_path_buf(), config, shutdown_signal()).await
+}
+
+// Serve on the socket at `path` until the shutdown future resolves, removing
+// the socket file afterwards
+async fn serve_unix_until(
+    path: PathBuf,
+    config: ServerConfig,
+    shutdown: impl Future<Output = std::io::Result<()>>,
+) -> Result<(), PrimeTimeError> {
+    config.validate_lines()?;
+
+    if config.unlink_stale_socket {
+        remove_stale_socket(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    tracing::info!("Listening on {}", path.display());
+
+    let result = serve_until(listener, config, shutdown).await;
+
+    // the socket file outlives the listener
+    if let Err(e) = std::fs::remove_file(&path) {
+        tracing::warn!("Failed to remove socket {}: {}", path.display(), e);
+    }
+
+    result
+}
+
+// A socket file left behind by a server that didn't shut down cleanly stops
+// a new one binding to the same path. Anything other than a socket is left
+// alone in case the path is a mistake.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            tracing::info!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    // a socket path in the temp dir that no other test uses
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("prime_time-{}-{name}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = socket_path("unix");
+
+        // left behind by an earlier server
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_unix_until(
+            path.clone(),
+            ServerConfig::default(),
+            async {
+                let _ = signal.await;
+                Ok(())
+            },
+        ));
+
+        // wait for the server to bind
+        let client = loop {
+            match UnixStream::connect(&path).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(line, r#"{"method":"isPrime","prime":true}"#);
+
+        drop((lines, writer));
+        shutdown.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_remove_stale_socket_leaves_other_files() {
+        let path = socket_path("regular");
+        std::fs::write(&path, "not a socket").unwrap();
+
+        remove_stale_socket(&path).unwrap();
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}